@@ -1,18 +1,60 @@
 use ansi_to_tui::IntoText as _;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use crossterm::event::{self, Event, KeyCode, KeyEventKind};
 use crossterm::terminal;
 use ratatui::{
-    layout::{Constraint, Layout, Position},
+    layout::{Constraint, Layout, Position, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     DefaultTerminal, Frame,
 };
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unicode_width::UnicodeWidthStr;
 
+use crate::cache::{DiffCache, DiffKey};
+use crate::clipboard;
 use crate::config::Config;
 use crate::fuzzy::FuzzyMatcher;
-use crate::git::{ChangedFile, FileStatus, Repository};
+use crate::git::{
+    parse_hunk_ranges, retain_hunks, ChangedFile, DiffAlgorithm, DiffError, DiffOutput,
+    DiffOverrides, DiffRequest, DiffResult, FileDiff, FileStatus, FileVersion, GitDiffSettings,
+    Hunk, LineKind, Repository,
+};
+use crate::highlight::{self, DiffHighlights};
+use crate::moved::{MovedLines, MovedSearch};
+use crate::prefetch::Prefetcher;
+use crate::render::{self, DiffLayout, DiffStyles, Gutter, RowSource};
+
+/// How long the terminal size must stay unchanged before width-dependent diffs are regenerated
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// How often to check for finished background diffs while waiting for input
+const PREFETCH_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest to wait for a background diff before computing it in the foreground
+const PREFETCH_MAX_WAIT: Duration = Duration::from_secs(10);
+
+/// Longest a jump to a moved line waits for the search for moved lines to finish
+const MOVED_JUMP_WAIT: Duration = Duration::from_secs(2);
+
+/// Lines of context added or removed by one press of `+`/`-`
+const CONTEXT_STEP: u32 = 3;
+
+/// Unchanged lines revealed around a hunk by one press of `K`/`J`
+const EXPAND_STEP: u32 = 10;
+
+/// Columns moved by one press of `h`/`l`
+const HSCROLL_STEP: u16 = 8;
+
+/// Upper bound on the notice area above the diff, so it never hides the diff itself
+const MAX_NOTICE_LINES: usize = 6;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Screen {
@@ -31,17 +73,75 @@ pub struct App {
     pub search_query: String,
     pub fuzzy_matcher: FuzzyMatcher,
     pub diff_content: Vec<u8>,
+    /// Structured diff when the built-in renderer is in use
+    pub diff_model: Option<FileDiff>,
+    pub diff_styles: DiffStyles,
+    /// Syntax colors for `diff_model`, when available
+    pub diff_highlights: Option<DiffHighlights>,
+    pub diff_layout: DiffLayout,
+    /// Whitespace and context settings chosen in the diff view, kept across files
+    pub diff_overrides: DiffOverrides,
     pub diff_lines: Vec<Line<'static>>,
+    /// Line numbers shown in each of `diff_lines`; for other tools' output, as far
+    /// as its hunk headers tell
+    pub diff_rows: Vec<RowSource>,
+    /// Moved lines across the changeset, found when the built-in diff first needs them
+    pub moved_lines: Option<MovedLines>,
+    /// Background search for moved lines, while it runs
+    pub moved_search: Option<MovedSearch>,
     pub diff_scroll: u16,
+    /// Row of `diff_lines` under the cursor, kept in view
+    pub diff_cursor: usize,
+    /// Row where the visual selection started; it runs to the cursor
+    pub diff_selection: Option<usize>,
+    /// Rows of the diff that fit on screen, as of the last draw
+    pub diff_view_height: usize,
+    /// Columns inside the diff border, to work out how many rows wrapped lines take
+    pub diff_view_width: usize,
+    /// Columns scrolled to the right, when lines aren't wrapped
+    pub diff_hscroll: u16,
+    /// Wrap long lines instead of cutting them off at the edge
+    pub diff_wrap: bool,
+    /// Show old and new line numbers left of the diff
+    pub diff_line_numbers: bool,
+    /// Hunks of the built-in diff shown as a one-line summary, by index
+    pub diff_folded: HashSet<usize>,
+    /// Problems encountered while producing the current diff, shown above it
+    pub diff_notice: Vec<String>,
+    /// Whether the current diff was cut short by a timeout or output limit
+    pub diff_truncated: bool,
+    /// Whether the current diff was loaded ignoring limits
+    pub diff_load_full: bool,
+    /// Versions of the file the current diff was computed from
+    pub diff_version: Option<FileVersion>,
+    /// Terminal width the current diff was formatted for, if it depends on it
+    pub diff_width: Option<u16>,
+    /// Diff view height the current diff was formatted for, if it depends on it
+    pub diff_height: Option<u16>,
+    pub diff_cache: DiffCache,
+    pub prefetcher: Prefetcher,
+    /// When the last resize event arrived, while waiting for resizing to settle
+    pub pending_resize: Option<Instant>,
+    /// Short message shown in the status line instead of the key hints
+    pub status_message: Option<String>,
+    /// Files marked in the file list for exporting as a patch
+    pub marked_files: BTreeSet<String>,
+    /// Hunks marked in the diff view for exporting, by file
+    pub marked_hunks: BTreeMap<String, HunkMarks>,
+    /// Path being typed for the exported patch, while asking for it
+    pub export_prompt: Option<String>,
     pub selected_file: Option<String>,
     pub config: Config,
     pub needs_redraw: bool,
+    pub repository: Repository,
+    /// Parent repositories and the submodule path we descended through, innermost last
+    pub parent_repos: Vec<(Repository, String)>,
 }
 
 impl App {
-    pub fn new() -> Result<Self> {
+    pub fn new(repo_dir: &Path) -> Result<Self> {
         let config = Config::load();
-        let repository = Repository::open_current_dir()?;
+        let repository = Repository::discover(repo_dir)?;
         let files = repository.get_changed_files()?;
         let file_paths: Vec<String> = files.iter().map(|f| f.path.clone()).collect();
         let filtered_indices: Vec<usize> = (0..files.len()).collect();
@@ -62,11 +162,46 @@ impl App {
             search_query: String::new(),
             fuzzy_matcher: FuzzyMatcher::new(),
             diff_content: Vec::new(),
+            diff_model: None,
+            diff_styles: DiffStyles::from_settings(&repository.diff_settings()),
+            diff_highlights: None,
+            diff_overrides: DiffOverrides::default(),
+            diff_layout: if config.diff.side_by_side {
+                DiffLayout::SideBySide
+            } else {
+                DiffLayout::Unified
+            },
             diff_lines: Vec::new(),
+            diff_rows: Vec::new(),
+            moved_lines: None,
+            moved_search: None,
             diff_scroll: 0,
+            diff_cursor: 0,
+            diff_selection: None,
+            diff_view_height: diff_height() as usize,
+            diff_view_width: diff_width() as usize,
+            diff_hscroll: 0,
+            diff_wrap: false,
+            diff_line_numbers: config.diff.line_numbers,
+            diff_folded: HashSet::new(),
+            diff_notice: Vec::new(),
+            diff_truncated: false,
+            diff_load_full: false,
+            diff_version: None,
+            diff_width: None,
+            diff_height: None,
+            diff_cache: DiffCache::new(config.diff.cache_max_bytes),
+            prefetcher: Prefetcher::new(),
+            pending_resize: None,
+            status_message: None,
+            marked_files: BTreeSet::new(),
+            marked_hunks: BTreeMap::new(),
+            export_prompt: None,
             selected_file: None,
             config,
             needs_redraw: false,
+            repository,
+            parent_repos: Vec::new(),
         })
     }
 
@@ -87,6 +222,24 @@ impl App {
             Screen::FileList => self.draw_file_list(frame),
             Screen::DiffView => self.draw_diff_view(frame),
         }
+        if let Some(path) = &self.export_prompt {
+            // Over the status line, which both screens end with
+            let area = frame.area();
+            let line = Rect::new(area.x, area.bottom().saturating_sub(1), area.width, 1);
+            let label = " Export patch to: ";
+            frame.render_widget(Clear, line);
+            frame.render_widget(
+                Paragraph::new(Line::from(vec![
+                    Span::styled(label, Style::default().fg(Color::Cyan)),
+                    Span::raw(path.as_str()),
+                ])),
+                line,
+            );
+            frame.set_cursor_position(Position::new(
+                line.x + (label.len() + path.width()) as u16,
+                line.y,
+            ));
+        }
     }
 
     fn draw_file_list(&mut self, frame: &mut Frame) {
@@ -133,23 +286,37 @@ impl App {
                     FileStatus::Deleted => ("D", Color::Red),
                     FileStatus::Renamed => ("R", Color::Cyan),
                     FileStatus::Untracked => ("?", Color::Gray),
+                    FileStatus::Submodule => ("S", Color::Magenta),
                 };
                 let line = Line::from(vec![
-                    Span::styled(
-                        format!("{} ", status_char.0),
-                        Style::default().fg(status_char.1),
-                    ),
+                    Span::styled(status_char.0, Style::default().fg(status_char.1)),
+                    self.mark_span(&file.path),
+                    Span::raw(" "),
                     Span::raw(&file.path),
                 ]);
                 ListItem::new(line)
             })
             .collect();
 
-        let title = format!(
-            " Changed Files ({}/{}) ",
-            self.filtered_indices.len(),
-            self.files.len()
-        );
+        let title = if self.parent_repos.is_empty() {
+            format!(
+                " Changed Files ({}/{}) ",
+                self.filtered_indices.len(),
+                self.files.len()
+            )
+        } else {
+            let submodule_path: Vec<&str> = self
+                .parent_repos
+                .iter()
+                .map(|(_, path)| path.as_str())
+                .collect();
+            format!(
+                " Changed Files [{}] ({}/{}) ",
+                submodule_path.join("/"),
+                self.filtered_indices.len(),
+                self.files.len()
+            )
+        };
         let list = List::new(items)
             .block(Block::default().title(title).borders(Borders::ALL))
             .highlight_style(
@@ -163,59 +330,368 @@ impl App {
 
         let help_text = if self.search_mode {
             " Type to search | Enter: select | Esc: cancel "
+        } else if !self.parent_repos.is_empty() {
+            " j/k: move | Enter: view diff | e: edit | /: search | r: refresh | Esc: leave submodule | q: quit | Space: mark | x: export"
         } else {
-            " j/k: move | Enter: view diff | e: edit | /: search | q: quit"
+            " j/k: move | Enter: view diff | e: edit | /: search | r: refresh | q: quit | Space: mark | x: export"
+        };
+        let help = match (&self.status_message, self.search_mode) {
+            (Some(message), false) => {
+                Paragraph::new(format!(" {message}")).style(Style::default().fg(Color::Yellow))
+            }
+            _ => Paragraph::new(help_text).style(Style::default().fg(Color::DarkGray)),
         };
-        let help = Paragraph::new(help_text).style(Style::default().fg(Color::DarkGray));
         frame.render_widget(help, help_area);
     }
 
     fn draw_diff_view(&mut self, frame: &mut Frame) {
         let area = frame.area();
 
-        let chunks = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).split(area);
+        let notice_height = self.diff_notice.len().min(MAX_NOTICE_LINES) as u16;
+        let chunks = Layout::vertical([
+            Constraint::Length(notice_height),
+            Constraint::Min(1),
+            Constraint::Length(1),
+        ])
+        .split(area);
+
+        let notice: Vec<Line> = self
+            .diff_notice
+            .iter()
+            .map(|line| Line::styled(line.as_str(), Style::default().fg(Color::Yellow)))
+            .collect();
+        frame.render_widget(Paragraph::new(notice), chunks[0]);
+        let chunks = &chunks[1..];
 
         let title = format!(" {} ", self.selected_file.as_deref().unwrap_or("Diff"));
 
         let visible_height = chunks[0].height.saturating_sub(2) as usize;
-        let visible_lines: Vec<Line> = self
-            .diff_lines
-            .iter()
+        self.diff_view_height = visible_height;
+        let visible_width = chunks[0].width.saturating_sub(2) as usize;
+        self.diff_view_width = visible_width;
+        let gutter = self.gutter();
+        let selection = self.selected_rows();
+        let visible_lines: Vec<Line> = (0..self.diff_lines.len())
             .skip(self.diff_scroll as usize)
             .take(visible_height)
-            .cloned()
+            .map(|idx| {
+                let mut line = self.display_line(idx, gutter);
+                let selected = self.diff_selection.is_some() && selection.contains(&idx);
+                if idx == self.diff_cursor || selected {
+                    // Highlight the whole row, not just its text
+                    let padding = visible_width.saturating_sub(line.width());
+                    if padding > 0 {
+                        line.spans.push(Span::raw(" ".repeat(padding)));
+                    }
+                }
+                if idx == self.diff_cursor {
+                    line = line.patch_style(Modifier::REVERSED);
+                } else if selected {
+                    for span in &mut line.spans {
+                        span.style = span.style.bg(Color::DarkGray);
+                    }
+                }
+                line
+            })
             .collect();
-
-        let diff = Paragraph::new(visible_lines)
-            .block(Block::default().title(title).borders(Borders::ALL));
+        let diff = Paragraph::new(visible_lines);
+        let diff = if self.diff_wrap {
+            diff.wrap(Wrap { trim: false })
+        } else {
+            diff
+        };
+        let diff = diff.block(Block::default().title(title).borders(Borders::ALL));
 
         frame.render_widget(diff, chunks[0]);
 
         let total_lines = self.diff_lines.len();
-        let current_line = self.diff_scroll as usize + 1;
-        let help = Paragraph::new(format!(
-            " j/k: scroll | e: edit | Esc: back | q: quit | Line {}/{} ",
-            current_line.min(total_lines),
-            total_lines
-        ))
-        .style(Style::default().fg(Color::DarkGray));
+        let current_line = self.diff_cursor + 1;
+        let submodule_hint = if self.selected_is_submodule() {
+            "Enter: open submodule | "
+        } else {
+            ""
+        };
+        // Whitespace and context settings come first so a narrow terminal can't hide them
+        let settings = self
+            .overrides_label()
+            .map(|label| Span::styled(format!(" [{label}]"), Style::default().fg(Color::Cyan)))
+            .unwrap_or_default();
+        let mut position = format!("Line {}/{}", current_line.min(total_lines), total_lines);
+        if self.diff_hscroll > 0 && !self.diff_wrap {
+            position.push_str(&format!(", Col {}", self.diff_hscroll + 1));
+        }
+        let help = match &self.status_message {
+            Some(message) => Span::styled(
+                format!(" {message} | Esc: back | {position} "),
+                Style::default().fg(Color::Yellow),
+            ),
+            None if self.diff_selection.is_some() => Span::styled(
+                format!(
+                    " Visual: {} lines | j/k: extend | e: edit | v/Esc: cancel | {position} ",
+                    self.selected_rows().count()
+                ),
+                Style::default().fg(Color::Cyan),
+            ),
+            None => Span::styled(
+                format!(
+                    " j/k: move | n/N: next/prev file | s: split | i: words | {submodule_hint}e: edit | Esc: back | q: quit | {position} "
+                ),
+                Style::default().fg(Color::DarkGray),
+            ),
+        };
+        let help = Paragraph::new(Line::from(vec![settings, help]));
         frame.render_widget(help, chunks[1]);
     }
 
+    /// Diff line `idx` as drawn, before highlighting the cursor and selection
+    fn display_line(&self, idx: usize, gutter: Option<Gutter>) -> Line<'static> {
+        let line = &self.diff_lines[idx];
+        let mut line = if self.diff_wrap {
+            line.clone()
+        } else {
+            // The gutter stays put while the text scrolls sideways
+            let text_width = self
+                .diff_view_width
+                .saturating_sub(gutter.map_or(0, |g| g.width()));
+            render::clip_line(line, self.diff_hscroll as usize, text_width)
+        };
+        if let Some(gutter) = gutter {
+            line.spans.insert(0, gutter.span(self.diff_rows.get(idx)));
+        }
+        if self.hunk_row_marked(idx) {
+            line.spans
+                .push(Span::styled(" ● marked", Style::default().fg(Color::Green)));
+        }
+        line
+    }
+
+    /// Screen rows each diff line takes up: one, or more for long lines when wrapping
+    fn row_heights(&self) -> impl Fn(usize) -> usize + '_ {
+        let gutter = if self.diff_wrap { self.gutter() } else { None };
+        move |idx| {
+            if !self.diff_wrap || idx >= self.diff_lines.len() {
+                return 1;
+            }
+            Paragraph::new(self.display_line(idx, gutter))
+                .wrap(Wrap { trim: false })
+                .line_count(self.diff_view_width as u16)
+                .max(1)
+        }
+    }
+
+    /// Number of diff lines that fit in the view from `top` down, at least one
+    fn lines_in_view(&self, top: usize) -> usize {
+        let height = self.row_heights();
+        let mut used = 0;
+        let mut count = 0;
+        for idx in top..top + self.diff_view_height.max(1) {
+            used += height(idx);
+            if used > self.diff_view_height && count > 0 {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+
     fn handle_events(&mut self) -> Result<()> {
-        if let Event::Key(key) = event::read()? {
-            if key.kind != KeyEventKind::Press {
+        if let Some(resized_at) = self.pending_resize {
+            let remaining = RESIZE_DEBOUNCE.saturating_sub(resized_at.elapsed());
+            if !event::poll(remaining)? {
+                self.pending_resize = None;
+                self.rerender_for_size();
+                return Ok(());
+            }
+        }
+        // Wake up regularly to collect background work while idle
+        while (self.prefetcher.has_pending() || self.moved_search.is_some())
+            && !event::poll(PREFETCH_POLL_INTERVAL)?
+        {
+            self.collect_prefetched(None);
+            if self.collect_moved_lines(Duration::ZERO) {
                 return Ok(());
             }
+        }
+
+        match event::read()? {
+            Event::Key(key) => {
+                if key.kind != KeyEventKind::Press {
+                    return Ok(());
+                }
 
-            match self.screen {
-                Screen::FileList => self.handle_file_list_keys(key.code),
-                Screen::DiffView => self.handle_diff_view_keys(key.code),
+                if self.export_prompt.is_some() {
+                    self.handle_export_prompt_keys(key.code);
+                    return Ok(());
+                }
+                match self.screen {
+                    Screen::FileList => self.handle_file_list_keys(key.code),
+                    Screen::DiffView => self.handle_diff_view_keys(key.code),
+                }
+            }
+            Event::Resize(..)
+                if self.screen == Screen::DiffView
+                    && (self.diff_width.is_some() || self.diff_height.is_some()) =>
+            {
+                self.pending_resize = Some(Instant::now());
             }
+            _ => {}
         }
         Ok(())
     }
 
+    /// Regenerate width- or height-dependent output for the new terminal size,
+    /// keeping the same logical line at the top of the view
+    fn rerender_for_size(&mut self) {
+        let width_changed = self.diff_width.is_some_and(|width| width != diff_width());
+        let height_changed = self
+            .diff_height
+            .is_some_and(|height| height != diff_height());
+        if !width_changed && !height_changed {
+            return;
+        }
+
+        // The built-in diff only needs laying out again, which keeps expanded context
+        if self.diff_model.is_some() {
+            let anchor = self.scroll_anchor();
+            self.render_model();
+            self.restore_scroll(&anchor);
+            return;
+        }
+        // Output for sizes we have seen before comes from the cache
+        self.reload_diff();
+    }
+
+    /// Load the current diff again, keeping the same logical line at the top of the view
+    fn reload_diff(&mut self) {
+        let anchor = self.scroll_anchor();
+        self.load_diff(self.diff_load_full);
+        self.restore_scroll(&anchor);
+    }
+
+    /// Apply a change to the algorithm, whitespace or context settings and regenerate
+    /// the diff. `update` gets the settings that apply without any overrides.
+    fn update_overrides(&mut self, update: impl FnOnce(&mut DiffOverrides, &GitDiffSettings)) {
+        let defaults = self.base_diff_settings();
+        update(&mut self.diff_overrides, &defaults);
+        // Settings that are back to their defaults aren't worth pointing out
+        if self.diff_overrides.algorithm == Some(defaults.algorithm) {
+            self.diff_overrides.algorithm = None;
+        }
+        if self.diff_overrides.context_lines == Some(defaults.context_lines) {
+            self.diff_overrides.context_lines = None;
+        }
+        // Ignoring whitespace or changing the algorithm changes what counts as moved,
+        // and any of these settings can change the hunks
+        self.moved_lines = None;
+        self.moved_search = None;
+        self.diff_folded.clear();
+        self.reload_diff();
+    }
+
+    /// The git diff settings with the configured algorithm, before any overrides
+    fn base_diff_settings(&self) -> GitDiffSettings {
+        let mut settings = self.repository.diff_settings();
+        if let Some(algorithm) = self.config.diff.algorithm {
+            settings.algorithm = algorithm;
+        }
+        settings
+    }
+
+    /// Non-default whitespace and context settings, for the status line
+    fn overrides_label(&self) -> Option<String> {
+        let overrides = &self.diff_overrides;
+        let mut parts = Vec::new();
+        if let Some(algorithm) = overrides.algorithm {
+            parts.push(algorithm.name().to_string());
+        }
+        if let Some(whitespace) = overrides.whitespace.label() {
+            parts.push(whitespace.to_string());
+        }
+        if overrides.whole_file {
+            parts.push("whole file".to_string());
+        } else if let Some(context_lines) = overrides.context_lines {
+            parts.push(format!("context {context_lines}"));
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+
+    fn scroll_anchor(&self) -> ScrollAnchor {
+        let top = self.diff_scroll as usize;
+        let cursor = self.diff_cursor;
+        ScrollAnchor {
+            key: self.diff_lines.get(top).map(anchor_key).unwrap_or_default(),
+            ratio: top as f64 / self.diff_lines.len().max(1) as f64,
+            cursor_key: self
+                .diff_lines
+                .get(cursor)
+                .map(anchor_key)
+                .unwrap_or_default(),
+            cursor_row: self.diff_rows.get(cursor).copied().unwrap_or_default(),
+            cursor_offset: cursor.saturating_sub(top),
+        }
+    }
+
+    fn restore_scroll(&mut self, anchor: &ScrollAnchor) {
+        let estimate = (anchor.ratio * self.diff_lines.len() as f64) as usize;
+        let max_scroll = self.diff_lines.len().saturating_sub(1);
+        self.diff_scroll = self
+            .find_line(&anchor.key, estimate)
+            .unwrap_or(estimate)
+            .min(max_scroll) as u16;
+        self.restore_cursor(anchor);
+    }
+
+    /// Put the cursor back on the diff line it was on, at the same height in the view.
+    /// Line numbers find it in another layout; its text finds it after the file changed.
+    fn restore_cursor(&mut self, anchor: &ScrollAnchor) {
+        let estimate = self.diff_scroll as usize + anchor.cursor_offset;
+        let by_number = self.row_by_number(&anchor.cursor_row);
+        let row = match by_number {
+            Some(row) if same_text(&anchor_key(&self.diff_lines[row]), &anchor.cursor_key) => {
+                Some(row)
+            }
+            _ => self.find_line(&anchor.cursor_key, by_number.unwrap_or(estimate)),
+        };
+        match row {
+            Some(row) => {
+                self.diff_cursor = row;
+                self.diff_scroll = row.saturating_sub(anchor.cursor_offset) as u16;
+            }
+            None => {
+                self.diff_cursor = estimate.min(self.diff_lines.len().saturating_sub(1));
+            }
+        }
+    }
+
+    /// Row showing the new line number of `source`, or else its old one
+    fn row_by_number(&self, source: &RowSource) -> Option<usize> {
+        let (old, new) = (source.old_lineno, source.new_lineno);
+        self.diff_rows
+            .iter()
+            .position(|row| new.is_some() && row.new_lineno == new)
+            .or_else(|| {
+                self.diff_rows
+                    .iter()
+                    .position(|row| old.is_some() && row.old_lineno == old)
+            })
+    }
+
+    /// Row closest to `near` whose text starts like `key`
+    fn find_line(&self, key: &str, near: usize) -> Option<usize> {
+        if key.is_empty() {
+            return None;
+        }
+        self.diff_lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| {
+                let line_key = anchor_key(line);
+                !line_key.is_empty() && (line_key.starts_with(key) || key.starts_with(&line_key))
+            })
+            .min_by_key(|(idx, _)| idx.abs_diff(near))
+            .map(|(idx, _)| idx)
+    }
+
     fn handle_file_list_keys(&mut self, code: KeyCode) {
         if self.search_mode {
             match code {
@@ -243,15 +719,20 @@ impl App {
                 _ => {}
             }
         } else {
+            self.status_message = None;
             match code {
                 KeyCode::Char('q') => self.running = false,
+                KeyCode::Char(' ') => self.toggle_file_mark(),
+                KeyCode::Char('x') => self.start_export(),
                 KeyCode::Char('j') | KeyCode::Down => self.select_next(),
                 KeyCode::Char('k') | KeyCode::Up => self.select_previous(),
                 KeyCode::Char('/') => {
                     self.search_mode = true;
                 }
                 KeyCode::Char('e') => self.open_selected_in_editor(),
+                KeyCode::Char('r') => self.refresh(),
                 KeyCode::Enter => self.open_diff(),
+                KeyCode::Esc => self.leave_submodule(),
                 _ => {}
             }
         }
@@ -262,35 +743,652 @@ impl App {
             KeyCode::Char('q') => {
                 self.running = false;
             }
+            KeyCode::Esc if self.diff_selection.is_some() => {
+                self.diff_selection = None;
+            }
             KeyCode::Esc => {
                 self.screen = Screen::FileList;
+                self.status_message = None;
                 self.diff_scroll = 0;
+                self.diff_cursor = 0;
             }
-            KeyCode::Char('j') | KeyCode::Down => {
-                let max_scroll = self.diff_lines.len().saturating_sub(1);
-                self.diff_scroll = (self.diff_scroll + 1).min(max_scroll as u16);
+            KeyCode::Char(' ') => self.toggle_hunk_marks(),
+            KeyCode::Char('x') => self.start_export(),
+            KeyCode::Char('v') => {
+                self.diff_selection = match self.diff_selection {
+                    Some(_) => None,
+                    None => Some(self.diff_cursor),
+                };
             }
-            KeyCode::Char('k') | KeyCode::Up => {
-                self.diff_scroll = self.diff_scroll.saturating_sub(1);
+            KeyCode::Char('V') => self.select_hunk(),
+            KeyCode::Char('y') => {
+                let text = match self.selection_patch() {
+                    Ok(text) => text,
+                    Err(e) => {
+                        self.status_message = Some(format!("{e:#}"));
+                        return;
+                    }
+                };
+                let count = self.selected_rows().count();
+                let what = if count == 1 {
+                    "1 line".to_string()
+                } else {
+                    format!("{count} lines")
+                };
+                self.diff_selection = None;
+                self.copy(&text, &what);
+            }
+            KeyCode::Char('Y') => match self.hunk_patch() {
+                Ok(text) => self.copy(&text, "the hunk"),
+                Err(e) => self.status_message = Some(format!("{e:#}")),
+            },
+            KeyCode::Char('P') => {
+                let file_diff = match &self.diff_model {
+                    Some(file_diff) => Ok(file_diff.clone()),
+                    None => self.shown_file_diff(),
+                };
+                match file_diff {
+                    Ok(file_diff) => {
+                        let text = String::from_utf8_lossy(&file_diff.to_patch()).into_owned();
+                        self.copy(&text, "the patch");
+                    }
+                    Err(e) => self.status_message = Some(format!("{e:#}")),
+                }
             }
+            KeyCode::Char('j') | KeyCode::Down => self.move_cursor(1),
+            KeyCode::Char('k') | KeyCode::Up => self.move_cursor(-1),
             KeyCode::Char('d') | KeyCode::PageDown => {
                 let max_scroll = self.diff_lines.len().saturating_sub(1);
                 self.diff_scroll = (self.diff_scroll + 20).min(max_scroll as u16);
+                self.diff_cursor = (self.diff_cursor + 20).min(max_scroll);
             }
             KeyCode::Char('u') | KeyCode::PageUp => {
                 self.diff_scroll = self.diff_scroll.saturating_sub(20);
+                self.diff_cursor = self.diff_cursor.saturating_sub(20);
+            }
+            KeyCode::Char('h') | KeyCode::Left => {
+                self.diff_hscroll = self.diff_hscroll.saturating_sub(HSCROLL_STEP);
+            }
+            KeyCode::Char('l') | KeyCode::Right if !self.diff_wrap => {
+                // Stop once the longest line ends at the right edge
+                let longest = self.diff_lines.iter().map(Line::width).max().unwrap_or(0);
+                let text_width = (diff_width() as usize)
+                    .saturating_sub(self.gutter().map_or(0, |gutter| gutter.width()));
+                let max_hscroll = longest.saturating_sub(text_width);
+                self.diff_hscroll =
+                    (self.diff_hscroll as usize + HSCROLL_STEP as usize).min(max_hscroll) as u16;
+            }
+            KeyCode::Char('W') => {
+                self.diff_wrap = !self.diff_wrap;
+            }
+            KeyCode::Char('#') => {
+                self.diff_line_numbers = !self.diff_line_numbers;
+                if self.diff_line_numbers && self.gutter().is_none() {
+                    self.status_message = Some(if self.split_model() {
+                        "The split layout already shows line numbers".to_string()
+                    } else {
+                        "No line numbers found in this diff".to_string()
+                    });
+                }
             }
             KeyCode::Char('g') | KeyCode::Home => {
                 self.diff_scroll = 0;
+                self.diff_cursor = 0;
             }
             KeyCode::Char('G') | KeyCode::End => {
-                self.diff_scroll = self.diff_lines.len().saturating_sub(1) as u16;
+                self.diff_cursor = self.diff_lines.len().saturating_sub(1);
+                self.diff_scroll = self.diff_cursor as u16;
             }
             KeyCode::Char('e') => {
                 self.open_in_editor();
             }
+            KeyCode::Char('r') => self.refresh(),
+            KeyCode::Char('n') => self.step_file(true),
+            KeyCode::Char('N') => self.step_file(false),
+            KeyCode::Char('s') => self.toggle_layout(DiffLayout::SideBySide),
+            KeyCode::Char('i') => self.toggle_layout(DiffLayout::WordDiff),
+            KeyCode::Char('a') => self.update_overrides(|overrides, defaults| {
+                let algorithm = overrides.algorithm.unwrap_or(defaults.algorithm);
+                overrides.algorithm = Some(algorithm.next());
+            }),
+            KeyCode::Char('w') => self.update_overrides(|overrides, _| {
+                overrides.whitespace = overrides.whitespace.next();
+            }),
+            KeyCode::Char('+') | KeyCode::Char('=') => {
+                self.update_overrides(|overrides, defaults| {
+                    let context = overrides.context_lines.unwrap_or(defaults.context_lines);
+                    overrides.context_lines = Some(context.saturating_add(CONTEXT_STEP));
+                    overrides.whole_file = false;
+                })
+            }
+            KeyCode::Char('-') => self.update_overrides(|overrides, defaults| {
+                let context = overrides.context_lines.unwrap_or(defaults.context_lines);
+                overrides.context_lines = Some(context.saturating_sub(CONTEXT_STEP));
+                overrides.whole_file = false;
+            }),
+            KeyCode::Char('F') => self.update_overrides(|overrides, _| {
+                overrides.whole_file = !overrides.whole_file;
+            }),
+            KeyCode::Char('m') => self.jump_to_move(),
+            KeyCode::Char(']') => self.step_hunk(true),
+            KeyCode::Char('[') => self.step_hunk(false),
+            KeyCode::Char('K') => self.expand_hunk(true),
+            KeyCode::Char('J') => self.expand_hunk(false),
+            KeyCode::Tab => self.toggle_fold(),
+            KeyCode::BackTab => self.toggle_fold_all(),
+            KeyCode::Char('L') if self.diff_truncated => {
+                self.load_diff(true);
+            }
+            KeyCode::Enter if self.selected_is_submodule() => {
+                self.enter_submodule();
+            }
             _ => {}
         }
+        self.keep_cursor_in_view();
+    }
+
+    /// Move the cursor by `delta` rows, scrolling to keep it in view
+    fn move_cursor(&mut self, delta: isize) {
+        let last = self.diff_lines.len().saturating_sub(1);
+        self.diff_cursor = self.diff_cursor.saturating_add_signed(delta).min(last);
+        let top = self.diff_scroll as usize;
+        if self.diff_cursor < top {
+            self.diff_scroll = self.diff_cursor as u16;
+        } else if self.diff_cursor >= top + self.lines_in_view(top) {
+            // Scroll just far enough to show the cursor line at the bottom
+            let height = self.row_heights();
+            let mut top = self.diff_cursor;
+            let mut used = height(top);
+            while top > 0 && used + height(top - 1) <= self.diff_view_height {
+                top -= 1;
+                used += height(top);
+            }
+            drop(height);
+            self.diff_scroll = top as u16;
+        }
+    }
+
+    /// Pull the cursor along when the view scrolled away from it
+    fn keep_cursor_in_view(&mut self) {
+        let top = self.diff_scroll as usize;
+        let bottom = top + self.lines_in_view(top) - 1;
+        self.diff_cursor = self
+            .diff_cursor
+            .clamp(top, bottom)
+            .min(self.diff_lines.len().saturating_sub(1));
+    }
+
+    /// Rows in the visual selection, or just the cursor row without one
+    fn selected_rows(&self) -> RangeInclusive<usize> {
+        let anchor = self.diff_selection.unwrap_or(self.diff_cursor);
+        anchor.min(self.diff_cursor)..=anchor.max(self.diff_cursor)
+    }
+
+    /// Marker in the file list for a file marked for export (`●`) or with marked hunks (`◐`)
+    fn mark_span(&self, path: &str) -> Span<'static> {
+        if self.marked_files.contains(path) {
+            Span::styled("●", Style::default().fg(Color::Green))
+        } else if self.marked_hunks.contains_key(path) {
+            Span::styled("◐", Style::default().fg(Color::Green))
+        } else {
+            Span::raw(" ")
+        }
+    }
+
+    /// Mark or unmark the highlighted file for export, then move to the next one
+    fn toggle_file_mark(&mut self) {
+        let Some(file) = self
+            .list_state
+            .selected()
+            .and_then(|list_idx| self.filtered_indices.get(list_idx))
+            .and_then(|&file_idx| self.files.get(file_idx))
+        else {
+            return;
+        };
+        if file.status == FileStatus::Submodule {
+            self.status_message = Some("Submodules can't be exported".to_string());
+            return;
+        }
+        if !self.marked_files.remove(&file.path) {
+            self.marked_files.insert(file.path.clone());
+        }
+        self.select_next();
+    }
+
+    /// Mark or unmark the hunks in the selection, or the hunk under the cursor, for export.
+    /// Marks name hunks of the diff without overrides, so only that diff can be marked.
+    fn toggle_hunk_marks(&mut self) {
+        let (Some(file_diff), Some(path)) = (&self.diff_model, self.selected_file.clone()) else {
+            self.status_message =
+                Some("Marking hunks is only available for the built-in diff".to_string());
+            return;
+        };
+        if self.diff_overrides != DiffOverrides::default() || self.diff_truncated {
+            self.status_message = Some(
+                "Hunks can only be marked in the full diff with the default settings".to_string(),
+            );
+            return;
+        }
+        let version = self.repository.file_version(&path);
+        if self.diff_version.as_ref() != Some(&version) {
+            self.status_message =
+                Some("The file changed since it was diffed | r: refresh".to_string());
+            return;
+        }
+        let shown: Vec<&Hunk> = self
+            .diff_rows
+            .get(self.selected_rows())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|row| row.hunk)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|idx| file_diff.hunks.get(idx))
+            .collect();
+        if shown.is_empty() {
+            self.status_message = Some("No hunk under the cursor".to_string());
+            return;
+        }
+
+        // Expanded hunks may hold several hunks of the diff, merged
+        let base = match self.builtin_file_diff(&path) {
+            Ok(base) => base,
+            Err(e) => {
+                self.status_message = Some(format!("{e:#}"));
+                return;
+            }
+        };
+        let ranges: BTreeSet<(u32, u32)> = base
+            .hunks
+            .iter()
+            .filter_map(|hunk| parse_hunk_ranges(&hunk.header).map(|(old, _)| old))
+            .filter(|&old| shown.iter().any(|hunk| contains_old_range(hunk, old)))
+            .collect();
+
+        let marks = self
+            .marked_hunks
+            .entry(path.clone())
+            .or_insert_with(|| HunkMarks {
+                version: version.clone(),
+                ranges: BTreeSet::new(),
+            });
+        if marks.version != version {
+            *marks = HunkMarks {
+                version,
+                ranges: BTreeSet::new(),
+            };
+        }
+        // Unmark when every hunk is already marked, otherwise mark them all
+        if ranges.is_subset(&marks.ranges) {
+            marks.ranges.retain(|range| !ranges.contains(range));
+        } else {
+            marks.ranges.extend(ranges);
+        }
+        let count = marks.ranges.len();
+        if count == 0 {
+            self.marked_hunks.remove(&path);
+        }
+        self.diff_selection = None;
+        self.status_message = Some(format!(
+            "{count} hunk{} of this file marked for export",
+            if count == 1 { "" } else { "s" }
+        ));
+    }
+
+    /// Whether `row` is the header of a hunk marked for export
+    fn hunk_row_marked(&self, row: usize) -> bool {
+        let Some(idx) = self.diff_rows.get(row).and_then(|source| source.hunk) else {
+            return false;
+        };
+        if row > 0 && self.diff_rows[row - 1].hunk == Some(idx) {
+            return false;
+        }
+        let (Some(file_diff), Some(path)) = (&self.diff_model, &self.selected_file) else {
+            return false;
+        };
+        let (Some(marks), Some(hunk)) = (self.marked_hunks.get(path), file_diff.hunks.get(idx))
+        else {
+            return false;
+        };
+        self.diff_version.as_ref() == Some(&marks.version)
+            && marks
+                .ranges
+                .iter()
+                .any(|&range| contains_old_range(hunk, range))
+    }
+
+    /// Ask where to write the patch of the marked files and hunks, or of the current
+    /// file if nothing is marked
+    fn start_export(&mut self) {
+        let name = if self.marked_files.is_empty() && self.marked_hunks.is_empty() {
+            let current = match self.screen {
+                Screen::DiffView => self.selected_file.clone(),
+                Screen::FileList => self.selected_path(),
+            };
+            let Some(path) = current else {
+                self.status_message = Some("Nothing to export".to_string());
+                return;
+            };
+            let name = Path::new(&path)
+                .file_name()
+                .map_or_else(|| "changes".into(), |name| name.to_string_lossy());
+            format!("{name}.patch")
+        } else {
+            "changes.patch".to_string()
+        };
+        self.export_prompt = Some(name);
+    }
+
+    fn handle_export_prompt_keys(&mut self, code: KeyCode) {
+        let Some(path) = &mut self.export_prompt else {
+            return;
+        };
+        match code {
+            KeyCode::Char(c) => path.push(c),
+            KeyCode::Backspace => {
+                path.pop();
+            }
+            KeyCode::Esc => self.export_prompt = None,
+            KeyCode::Enter => {
+                if let Some(path) = self.export_prompt.take() {
+                    self.export_patch(&path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Write the marked files and hunks (or the current file) to `target` as a patch
+    /// that `git apply` accepts. Existing files aren't overwritten.
+    fn export_patch(&mut self, target: &str) {
+        if target.is_empty() {
+            return;
+        }
+        if Path::new(target).exists() {
+            self.status_message = Some(format!("{target} already exists"));
+            return;
+        }
+
+        let mut files: Vec<String> = self.marked_files.iter().cloned().collect();
+        if files.is_empty() && self.marked_hunks.is_empty() {
+            let current = match self.screen {
+                Screen::DiffView => self.selected_file.clone(),
+                Screen::FileList => self.selected_path(),
+            };
+            files.extend(current);
+        }
+        // Marked hunks are taken from a fresh diff, unless the file changed since
+        let mut patches: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let mut changed = Vec::new();
+        for (path, marks) in &self.marked_hunks {
+            if self.marked_files.contains(path) {
+                continue;
+            }
+            if self.repository.file_version(path) != marks.version {
+                changed.push(path.clone());
+                continue;
+            }
+            let patch = match self.builtin_file_patch(path) {
+                Ok(patch) => patch,
+                Err(e) => {
+                    self.status_message = Some(format!("{e:#}"));
+                    return;
+                }
+            };
+            let marked = retain_hunks(&patch, |header| {
+                parse_hunk_ranges(header).is_some_and(|(old, _)| marks.ranges.contains(&old))
+            });
+            if let Some(marked) = marked {
+                patches.insert(path.clone(), marked);
+            }
+        }
+        for path in &changed {
+            self.marked_hunks.remove(path);
+        }
+        let dropped = match changed.len() {
+            0 => String::new(),
+            1 => format!(" | Dropped the marks in {}, which changed", changed[0]),
+            count => format!(" | Dropped the marks in {count} files that changed"),
+        };
+        if !files.is_empty() {
+            let settings = self.base_diff_settings();
+            match self.repository.export_diff(&files, &settings) {
+                // Whole files take the place of their marked hunks
+                Ok(diffs) => patches.extend(diffs),
+                Err(e) => {
+                    self.status_message = Some(format!("{e:#}"));
+                    return;
+                }
+            }
+        }
+        if patches.is_empty() {
+            self.status_message = Some(format!("Nothing to export{dropped}"));
+            return;
+        }
+
+        let patch: Vec<u8> = patches.values().flatten().copied().collect();
+        self.status_message = Some(match fs::write(target, patch) {
+            Ok(()) => format!(
+                "Wrote {} file{} to {target}{dropped}",
+                patches.len(),
+                if patches.len() == 1 { "" } else { "s" }
+            ),
+            Err(e) => format!("Failed to write {target}: {e}"),
+        });
+    }
+
+    /// Put `text` on the clipboard, reporting it as `what` in the status line
+    fn copy(&mut self, text: &str, what: &str) {
+        self.status_message = Some(match clipboard::copy(text, &self.config.clipboard) {
+            Ok(()) => format!("Copied {what}"),
+            Err(e) => format!("{e:#}"),
+        });
+    }
+
+    /// Unified diff text of the selected rows. The built-in diff gives the lines
+    /// behind them in the unified and split layouts, and whole hunks for folded ones;
+    /// other tools' output is copied as shown. Rows of the word diff that merge
+    /// changed lines have no lines of their own behind them, so they can't be copied.
+    fn selection_patch(&self) -> Result<String> {
+        let rows = self.selected_rows();
+        // An empty diff leaves nothing under the cursor
+        let lines = self
+            .diff_lines
+            .get(rows.clone())
+            .ok_or_else(|| anyhow!("Nothing to copy"))?;
+        let Some(file_diff) = &self.diff_model else {
+            return Ok(lines_text(lines));
+        };
+        let selected = self
+            .diff_rows
+            .get(rows.clone())
+            .ok_or_else(|| anyhow!("Nothing to copy"))?;
+        let merged = rows.clone().zip(selected).any(|(row, source)| {
+            source.old_lineno.is_none()
+                && source.new_lineno.is_none()
+                && source
+                    .hunk
+                    .is_some_and(|hunk| self.hunk_row(hunk) != Some(row))
+        });
+        if merged {
+            bail!("Changed lines can't be copied from the word diff | i: unified view");
+        }
+        let mut out = String::new();
+        if selected.iter().any(|row| row.hunk.is_none()) {
+            for line in &file_diff.header {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        for (idx, hunk) in file_diff.hunks.iter().enumerate() {
+            let header_selected = self.hunk_row(idx).is_some_and(|row| rows.contains(&row));
+            if header_selected && self.diff_folded.contains(&idx) {
+                out.push_str(&hunk.to_patch());
+                continue;
+            }
+            let mut included_lines = Vec::new();
+            let mut previous = false;
+            for line in &hunk.lines {
+                let included = match line.kind {
+                    // "No newline" markers go with the line before them
+                    LineKind::Marker => previous,
+                    _ => selected.iter().any(|row| {
+                        (line.old_lineno.is_some() && row.old_lineno == line.old_lineno)
+                            || (line.new_lineno.is_some() && row.new_lineno == line.new_lineno)
+                    }),
+                };
+                if included {
+                    included_lines.push(line.clone());
+                }
+                previous = included;
+            }
+            if header_selected {
+                // The header counts the lines that are copied, not the whole hunk
+                let mut part = Hunk {
+                    header: hunk.header.clone(),
+                    lines: included_lines,
+                };
+                if !part.lines.is_empty() {
+                    part.update_header();
+                }
+                out.push_str(&part.to_patch());
+            } else {
+                for line in &included_lines {
+                    out.push_str(&line.patch_line());
+                    out.push('\n');
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Unified diff text of the hunk under the cursor. For another tool's output, it is
+    /// the built-in diff's hunk at the cursor's line numbers, as the output itself may
+    /// not be a patch.
+    fn hunk_patch(&self) -> Result<String> {
+        let no_hunk = || anyhow!("No hunk under the cursor");
+        let cursor = *self.diff_rows.get(self.diff_cursor).ok_or_else(no_hunk)?;
+        let idx = cursor.hunk.ok_or_else(no_hunk)?;
+        if let Some(file_diff) = &self.diff_model {
+            return file_diff
+                .hunks
+                .get(idx)
+                .map(Hunk::to_patch)
+                .ok_or_else(no_hunk);
+        }
+
+        // A hunk header has no numbers, so any line of its hunk will do
+        let targets: Vec<RowSource> = if cursor.old_lineno.is_some() || cursor.new_lineno.is_some()
+        {
+            vec![cursor]
+        } else {
+            self.diff_rows
+                .iter()
+                .filter(|row| row.hunk == Some(idx))
+                .copied()
+                .collect()
+        };
+        let file_diff = self.shown_file_diff()?;
+        file_diff
+            .hunks
+            .iter()
+            .find(|hunk| {
+                hunk.lines.iter().any(|line| {
+                    targets.iter().any(|row| {
+                        (line.old_lineno.is_some() && row.old_lineno == line.old_lineno)
+                            || (line.new_lineno.is_some() && row.new_lineno == line.new_lineno)
+                    })
+                })
+            })
+            .map(Hunk::to_patch)
+            .ok_or_else(no_hunk)
+    }
+
+    /// Built-in diff of `path` without overrides, hunk for hunk as it is shown: the
+    /// histogram algorithm comes from git, like in `get_diff`
+    fn builtin_file_diff(&self, path: &str) -> Result<FileDiff> {
+        let settings = self.base_diff_settings();
+        if settings.algorithm == DiffAlgorithm::Histogram {
+            if let Ok(Some(file_diff)) = self.repository.git_file_diff(path, &settings) {
+                return Ok(file_diff);
+            }
+        }
+        self.repository.file_diff(path, &settings)
+    }
+
+    /// Patch of `path` with the same hunks as `builtin_file_diff`, byte for byte so
+    /// that `git apply` takes it whatever the file's encoding
+    fn builtin_file_patch(&self, path: &str) -> Result<Vec<u8>> {
+        let settings = self.base_diff_settings();
+        if settings.algorithm == DiffAlgorithm::Histogram {
+            if let Ok(Some(patch)) = self.repository.git_file_patch(path, &settings) {
+                return Ok(patch);
+            }
+        }
+        let patches = self
+            .repository
+            .export_diff(&[path.to_string()], &settings)?;
+        Ok(patches
+            .into_iter()
+            .next()
+            .map(|(_, patch)| patch)
+            .unwrap_or_default())
+    }
+
+    /// Built-in diff of the shown file, to copy patches from while another tool's
+    /// output is shown
+    fn shown_file_diff(&self) -> Result<FileDiff> {
+        let path = self
+            .selected_file
+            .as_deref()
+            .ok_or_else(|| anyhow!("No file selected"))?;
+        self.builtin_file_diff(path)
+    }
+
+    /// Select the rows of the hunk under the cursor, leaving the cursor on its last row
+    fn select_hunk(&mut self) {
+        let Some(hunk) = self
+            .diff_rows
+            .get(self.diff_cursor)
+            .and_then(|row| row.hunk)
+        else {
+            self.status_message = Some("No hunk under the cursor".to_string());
+            return;
+        };
+        let in_hunk = |row: &RowSource| row.hunk == Some(hunk);
+        let (Some(first), Some(last)) = (
+            self.diff_rows.iter().position(in_hunk),
+            self.diff_rows.iter().rposition(in_hunk),
+        ) else {
+            return;
+        };
+        self.diff_selection = Some(first);
+        self.diff_cursor = first;
+        self.move_cursor((last - first) as isize);
+    }
+
+    /// Line of the new version at `row`. Removed lines give the line now in
+    /// their place, and hunk headers the first line of their hunk.
+    fn new_lineno_at(&self, row: usize) -> Option<u32> {
+        let rows = &self.diff_rows;
+        let cursor = rows.get(row)?;
+        let same_hunk = |other: &&RowSource| cursor.hunk.is_none() || other.hunk == cursor.hunk;
+        rows[row..]
+            .iter()
+            .take_while(same_hunk)
+            .find_map(|row| row.new_lineno)
+            .or_else(|| {
+                rows[..row]
+                    .iter()
+                    .rev()
+                    .take_while(same_hunk)
+                    .find_map(|row| row.new_lineno)
+            })
+            // A folded hunk only has its header
+            .or_else(|| {
+                let hunk = self.diff_model.as_ref()?.hunks.get(cursor.hunk?)?;
+                Some(hunk.before().1 + 1)
+            })
     }
 
     fn select_next(&mut self) {
@@ -328,48 +1426,802 @@ impl App {
     }
 
     fn open_diff(&mut self) {
-        if let Some(list_idx) = self.list_state.selected() {
-            if let Some(&file_idx) = self.filtered_indices.get(list_idx) {
-                if let Some(file) = self.files.get(file_idx) {
-                    self.selected_file = Some(file.path.clone());
-                    // Get terminal width (subtract 2 for border)
-                    let width = terminal::size()
-                        .map(|(w, _)| w.saturating_sub(2))
-                        .unwrap_or(80);
-                    self.diff_content = crate::git::get_diff(&file.path, width, &self.config.diff);
-
-                    // Parse ANSI escape sequences into styled lines
-                    self.diff_lines = match self.diff_content.as_slice().into_text() {
-                        Ok(text) => text
-                            .lines
-                            .into_iter()
-                            .map(|line| {
-                                Line::from(
-                                    line.spans
-                                        .into_iter()
-                                        .map(|span| {
-                                            Span::styled(span.content.to_string(), span.style)
-                                        })
-                                        .collect::<Vec<_>>(),
-                                )
-                            })
-                            .collect(),
-                        Err(_) => {
-                            // Fallback: plain text without ANSI parsing
-                            String::from_utf8_lossy(&self.diff_content)
-                                .lines()
-                                .map(|s| Line::raw(s.to_string()))
-                                .collect()
+        if self.load_diff(false) {
+            self.diff_scroll = 0;
+            self.diff_cursor = 0;
+            self.screen = Screen::DiffView;
+        }
+    }
+
+    /// Compute the diff of the selected file. With `load_full`, timeouts and output
+    /// limits are ignored. Returns whether a file was selected.
+    fn load_diff(&mut self, load_full: bool) -> bool {
+        let Some(file) = self
+            .list_state
+            .selected()
+            .and_then(|list_idx| self.filtered_indices.get(list_idx))
+            .and_then(|&file_idx| self.files.get(file_idx))
+        else {
+            return false;
+        };
+
+        if self.selected_file.as_deref() != Some(file.path.as_str()) {
+            self.diff_hscroll = 0;
+            self.diff_folded.clear();
+        }
+        self.selected_file = Some(file.path.clone());
+        self.diff_load_full = load_full;
+        self.diff_version = None;
+        let result = if file.status == FileStatus::Submodule {
+            self.repository
+                .submodule_summary(&file.path)
+                .map(|summary| DiffResult {
+                    output: DiffOutput::Formatted(summary),
+                    fallback: None,
+                    warnings: Vec::new(),
+                    truncated: None,
+                    width: None,
+                    height: None,
+                    highlights: None,
+                })
+                .map_err(|e| DiffError::Git(format!("{e:#}")))
+        } else {
+            let width = diff_width();
+            let key = self.diff_key(&file.path, load_full);
+            self.diff_version = Some(key.version.clone());
+            if self.prefetcher.is_pending(&key) {
+                self.collect_prefetched(Some(&key));
+            }
+            match self.diff_cache.get(&key, width, diff_height()) {
+                Some(result) => Ok(result),
+                None => {
+                    let result = crate::git::get_diff(
+                        &self.repository,
+                        &DiffRequest {
+                            path: &key.path,
+                            width,
+                            height: diff_height(),
+                            load_full,
+                            overrides: key.overrides,
+                            cancel: None,
+                        },
+                        &self.config.diff,
+                    );
+                    if let Ok(result) = &result {
+                        self.diff_cache.insert(&key, result);
+                    }
+                    result
+                }
+            }
+        };
+        self.set_diff_result(result);
+        self.prefetch_neighbours();
+        true
+    }
+
+    fn diff_key(&self, path: &str, load_full: bool) -> DiffKey {
+        let (tool, args) = self.config.diff.tool_for(path);
+        DiffKey {
+            path: path.to_string(),
+            version: self.repository.file_version(path),
+            tool: tool.to_string(),
+            args: args.to_vec(),
+            width: None,
+            height: None,
+            load_full,
+            overrides: self.diff_overrides,
+        }
+    }
+
+    /// Start computing the diffs of the files before and after the selected one,
+    /// cancelling speculative work for files that are no longer next to it
+    fn prefetch_neighbours(&mut self) {
+        if !self.config.diff.prefetch {
+            return;
+        }
+        let Some(list_idx) = self.list_state.selected() else {
+            return;
+        };
+
+        let width = diff_width();
+        let mut wanted = Vec::new();
+        for neighbour in [list_idx + 1, list_idx.wrapping_sub(1)] {
+            let Some(file) = self
+                .filtered_indices
+                .get(neighbour)
+                .and_then(|&file_idx| self.files.get(file_idx))
+            else {
+                continue;
+            };
+            if file.status == FileStatus::Submodule {
+                continue;
+            }
+            let key = self.diff_key(&file.path, false);
+            if self.diff_cache.get(&key, width, diff_height()).is_some()
+                || self.repository.version_size(&key.version)
+                    > self.config.diff.prefetch_max_file_bytes
+            {
+                continue;
+            }
+            wanted.push(key);
+        }
+
+        self.prefetcher.retain(|key| wanted.contains(key));
+        let config = Arc::new(self.config.diff.clone());
+        for key in wanted {
+            self.prefetcher.request(
+                key,
+                self.repository.workdir().to_path_buf(),
+                width,
+                diff_height(),
+                Arc::clone(&config),
+            );
+        }
+    }
+
+    /// Move finished background diffs into the cache. With `until`, block until
+    /// that diff is done (or abandoned), giving up on it after `PREFETCH_MAX_WAIT`.
+    fn collect_prefetched(&mut self, until: Option<&DiffKey>) {
+        let deadline = Instant::now() + PREFETCH_MAX_WAIT;
+        loop {
+            let timeout = until.map(|_| PREFETCH_POLL_INTERVAL);
+            match self.prefetcher.next_result(timeout) {
+                Some((key, Ok(result))) => self.diff_cache.insert(&key, &result),
+                Some((_, Err(_))) => {}
+                None => match until {
+                    Some(key) if self.prefetcher.is_pending(key) => {
+                        if Instant::now() >= deadline {
+                            self.prefetcher.retain(|pending| pending != key);
+                            return;
                         }
-                    };
+                    }
+                    _ => return,
+                },
+            }
+        }
+    }
 
-                    self.diff_scroll = 0;
-                    self.screen = Screen::DiffView;
+    /// Show the diff of the next (or previous) file without going back to the list
+    fn step_file(&mut self, forward: bool) {
+        let Some(list_idx) = self.list_state.selected() else {
+            return;
+        };
+        let target = if forward {
+            list_idx + 1
+        } else {
+            list_idx.wrapping_sub(1)
+        };
+        if target < self.filtered_indices.len() {
+            self.list_state.select(Some(target));
+            self.load_diff(false);
+            self.diff_scroll = 0;
+            self.diff_cursor = 0;
+        }
+    }
+
+    /// Reload the list of changed files, keeping the selection, and drop cached
+    /// diffs whose files changed since they were computed
+    fn refresh(&mut self) {
+        let files = match self.repository.get_changed_files() {
+            Ok(files) => files,
+            Err(e) => {
+                self.status_message = Some(format!("{e:#}"));
+                return;
+            }
+        };
+
+        self.moved_lines = None;
+        self.moved_search = None;
+        let repository = &self.repository;
+        let changed: HashSet<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        self.diff_cache.invalidate(|path| {
+            changed
+                .contains(path)
+                .then(|| repository.file_version(path))
+        });
+
+        let query = std::mem::take(&mut self.search_query);
+        let selected = self.selected_path();
+        self.set_files(files, selected.as_deref());
+        if !query.is_empty() {
+            self.search_query = query;
+            self.update_filter();
+        }
+
+        if self.screen == Screen::DiffView {
+            let anchor = self.scroll_anchor();
+            if self.load_diff(self.diff_load_full) {
+                self.restore_scroll(&anchor);
+            } else {
+                self.screen = Screen::FileList;
+            }
+        }
+    }
+
+    /// Path of the file highlighted in the file list
+    fn selected_path(&self) -> Option<String> {
+        self.list_state
+            .selected()
+            .and_then(|list_idx| self.filtered_indices.get(list_idx))
+            .and_then(|&file_idx| self.files.get(file_idx))
+            .map(|file| file.path.clone())
+    }
+
+    fn set_diff_result(&mut self, result: Result<DiffResult, DiffError>) {
+        self.diff_notice.clear();
+        self.status_message = None;
+        self.diff_truncated = false;
+        self.diff_width = None;
+        self.diff_height = None;
+
+        match result {
+            Ok(result) => {
+                self.diff_width = result.width;
+                self.diff_height = result.height;
+                self.set_diff_output(result.output, result.highlights);
+                self.diff_notice.extend(result.warnings);
+                if let Some(e) = result.fallback {
+                    self.diff_notice.push(format!("{e}; showing built-in diff"));
+                    if let Some(details) = e.details() {
+                        self.diff_notice
+                            .extend(details.lines().map(|line| format!("  {line}")));
+                    }
+                    self.status_message = Some(format!(
+                        "{} failed; showing built-in diff",
+                        e.tool().unwrap_or("diff tool")
+                    ));
+                }
+                if let Some(truncation) = result.truncated {
+                    self.diff_truncated = true;
+                    self.diff_notice.push(format!(
+                        "Diff {truncation}; showing the first part. Press L to load the full output"
+                    ));
+                    self.status_message = Some("Output truncated | L: load full".to_string());
                 }
             }
+            Err(DiffError::EmptyDiff) => {
+                self.clear_diff();
+                self.status_message = Some("No changes to show".to_string());
+            }
+            Err(e) => {
+                self.clear_diff();
+                self.diff_lines =
+                    vec![Line::styled(e.to_string(), Style::default().fg(Color::Red))];
+                self.status_message = Some(e.to_string());
+            }
+        }
+    }
+
+    fn clear_diff(&mut self) {
+        self.diff_selection = None;
+        self.diff_content.clear();
+        self.diff_model = None;
+        self.diff_highlights = None;
+        self.diff_lines.clear();
+        self.diff_rows.clear();
+    }
+
+    fn set_diff_output(&mut self, output: DiffOutput, highlights: Option<DiffHighlights>) {
+        // Rows are about to change under the selection
+        self.diff_selection = None;
+        match output {
+            DiffOutput::Native(file_diff) => {
+                self.diff_content = file_diff.to_patch();
+                self.diff_highlights = highlights;
+                self.diff_model = Some(file_diff);
+                self.render_model();
+            }
+            DiffOutput::Formatted(content) => {
+                self.diff_content = content;
+                self.diff_model = None;
+                self.diff_highlights = None;
+
+                // Parse ANSI escape sequences into styled lines
+                self.diff_lines = match self.diff_content.as_slice().into_text() {
+                    Ok(text) => text
+                        .lines
+                        .into_iter()
+                        .map(|line| {
+                            Line::from(
+                                line.spans
+                                    .into_iter()
+                                    .map(|span| Span::styled(span.content.to_string(), span.style))
+                                    .collect::<Vec<_>>(),
+                            )
+                        })
+                        .collect(),
+                    Err(_) => {
+                        // Fallback: plain text without ANSI parsing
+                        String::from_utf8_lossy(&self.diff_content)
+                            .lines()
+                            .map(|s| Line::raw(s.to_string()))
+                            .collect()
+                    }
+                };
+                self.diff_rows = render::text_rows(&self.diff_lines);
+            }
+        }
+    }
+
+    fn highlight(&self, file_diff: &FileDiff) -> Option<DiffHighlights> {
+        let config = &self.config.diff;
+        if !config.syntax_highlight || !self.diff_styles.syntax {
+            return None;
+        }
+        let path = self.selected_file.as_deref()?;
+        highlight::highlight(path, file_diff, &config.syntax_theme)
+    }
+
+    /// Render the built-in diff in the current layout. A selection keeps starting on
+    /// the same line, and is dropped when that line is folded away.
+    fn render_model(&mut self) {
+        if self.diff_model.is_none() {
+            return;
+        }
+        let selection = self.diff_selection.and_then(|row| {
+            let source = *self.diff_rows.get(row)?;
+            Some((row, source, anchor_key(self.diff_lines.get(row)?)))
+        });
+        self.detect_moved_lines();
+        let Some(file_diff) = &self.diff_model else {
+            return;
+        };
+        let highlights = self.diff_highlights.as_ref();
+        let moves = self
+            .moved_lines
+            .as_ref()
+            .zip(self.selected_file.as_deref())
+            .and_then(|(moved, path)| moved.file(path));
+        let folded = &self.diff_folded;
+        let rendered = match self.diff_layout {
+            DiffLayout::Unified => {
+                render::unified(file_diff, &self.diff_styles, highlights, moves, folded)
+            }
+            DiffLayout::SideBySide => {
+                let width = diff_width();
+                // Re-rendered on resize like other width-dependent output
+                self.diff_width = Some(width);
+                render::side_by_side(
+                    file_diff,
+                    &self.diff_styles,
+                    highlights,
+                    moves,
+                    folded,
+                    width,
+                )
+            }
+            DiffLayout::WordDiff => render::word_diff(file_diff, &self.diff_styles, folded),
+        };
+        self.diff_lines = rendered.lines;
+        self.diff_rows = rendered.rows;
+        // Header rows have no line numbers, so they are found by their text
+        self.diff_selection = selection.and_then(|(near, source, key)| {
+            if source.old_lineno.is_some() || source.new_lineno.is_some() {
+                self.row_by_number(&source)
+            } else {
+                self.find_line(&key, near)
+            }
+        });
+    }
+
+    /// Start looking for moved lines across the changeset in the background, unless
+    /// that was done (or is under way) since the files or diff settings last changed
+    fn detect_moved_lines(&mut self) {
+        if self.moved_lines.is_some()
+            || self.moved_search.is_some()
+            || !self.config.diff.color_moved
+        {
+            return;
+        }
+        let settings = self
+            .base_diff_settings()
+            .with_overrides(&self.diff_overrides);
+        let workdir = self.repository.workdir().to_path_buf();
+        self.moved_search = Some(MovedSearch::start(workdir, settings));
+    }
+
+    /// Take the result of the search for moved lines, waiting up to `timeout` for it,
+    /// and color the built-in diff with it. Returns whether it arrived.
+    fn collect_moved_lines(&mut self, timeout: Duration) -> bool {
+        let Some(search) = &self.moved_search else {
+            return false;
+        };
+        let moved = match search.wait(timeout) {
+            Ok(moved) => moved,
+            Err(RecvTimeoutError::Timeout) => return false,
+            // The search failed; don't start it again until something changes
+            Err(RecvTimeoutError::Disconnected) => MovedLines::default(),
+        };
+        self.moved_search = None;
+        self.moved_lines = Some(moved);
+        if self.diff_model.is_some() {
+            let anchor = self.scroll_anchor();
+            self.render_model();
+            self.restore_scroll(&anchor);
+        }
+        true
+    }
+
+    /// Jump from the first moved line at or below the cursor to the other end of its
+    /// move, putting the cursor there, switching files if it moved to or from another one
+    fn jump_to_move(&mut self) {
+        if self.diff_model.is_none() {
+            self.status_message =
+                Some("Moved lines are only shown in the built-in diff".to_string());
+            return;
+        }
+        self.detect_moved_lines();
+        self.collect_moved_lines(MOVED_JUMP_WAIT);
+        if self.moved_search.is_some() {
+            self.status_message = Some("Still looking for moved lines".to_string());
+            return;
+        }
+        let target = self.moved_lines.as_ref().and_then(|moved| {
+            let file = moved.file(self.selected_file.as_deref()?)?;
+            self.diff_rows
+                .iter()
+                .skip(self.diff_cursor)
+                .find_map(|row| {
+                    if let Some(mv) = row.old_lineno.and_then(|lineno| file.moved_out(lineno)) {
+                        let target = RowSource {
+                            new_lineno: Some(mv.lineno),
+                            ..RowSource::default()
+                        };
+                        return Some((moved.path(mv.file).to_string(), target));
+                    }
+                    let mv = row.new_lineno.and_then(|lineno| file.moved_in(lineno))?;
+                    let target = RowSource {
+                        old_lineno: Some(mv.lineno),
+                        ..RowSource::default()
+                    };
+                    Some((moved.path(mv.file).to_string(), target))
+                })
+        });
+        let Some((path, target)) = target else {
+            self.status_message = Some("No moved lines below".to_string());
+            return;
+        };
+
+        if self.selected_file.as_deref() != Some(path.as_str()) && !self.show_file(&path) {
+            self.status_message = Some(format!("{path} is not in the file list"));
+            return;
+        }
+        self.diff_cursor = self.reveal_line(&target).unwrap_or(0);
+        self.diff_scroll = self.diff_cursor as u16;
+        self.status_message = Some(match (target.old_lineno, target.new_lineno) {
+            (Some(lineno), _) => format!("Moved from {path}:{lineno}"),
+            (_, lineno) => format!("Moved to {path}:{}", lineno.unwrap_or(0)),
+        });
+    }
+
+    /// Row showing the old line or new line of `target`, unfolding its hunk if needed
+    fn reveal_line(&mut self, target: &RowSource) -> Option<usize> {
+        let matches = |row: &RowSource| {
+            (target.old_lineno.is_some() && row.old_lineno == target.old_lineno)
+                || (target.new_lineno.is_some() && row.new_lineno == target.new_lineno)
+        };
+        if let Some(row) = self.diff_rows.iter().position(matches) {
+            return Some(row);
+        }
+        let hunk = self.diff_model.as_ref()?.hunks.iter().position(|hunk| {
+            hunk.lines.iter().any(|line| {
+                matches(&RowSource {
+                    old_lineno: line.old_lineno,
+                    new_lineno: line.new_lineno,
+                    ..RowSource::default()
+                })
+            })
+        })?;
+        if !self.diff_folded.remove(&hunk) {
+            return None;
+        }
+        self.render_model();
+        self.diff_rows.iter().position(matches)
+    }
+
+    /// Hunk under the cursor, or the first one below it
+    fn current_hunk(&self) -> Option<usize> {
+        self.diff_rows
+            .iter()
+            .skip(self.diff_cursor)
+            .find_map(|row| row.hunk)
+    }
+
+    /// First row of hunk `idx`
+    fn hunk_row(&self, idx: usize) -> Option<usize> {
+        self.diff_rows.iter().position(|row| row.hunk == Some(idx))
+    }
+
+    /// Fold or unfold the hunk under the cursor
+    fn toggle_fold(&mut self) {
+        if self.diff_model.is_none() {
+            self.status_message =
+                Some("Folding is only available for the built-in diff".to_string());
+            return;
+        }
+        let Some(hunk) = self.current_hunk() else {
+            return;
+        };
+        if !self.diff_folded.remove(&hunk) {
+            self.diff_folded.insert(hunk);
+        }
+        self.render_model();
+        // Rows above the hunk are unchanged, so only the view inside it needs moving
+        if let Some(row) = self.hunk_row(hunk) {
+            self.diff_scroll = self.diff_scroll.min(row as u16);
+            if self.diff_folded.contains(&hunk) || self.diff_cursor < row {
+                self.diff_cursor = row;
+            }
         }
     }
 
+    /// Fold every hunk, or unfold them all if they already are
+    fn toggle_fold_all(&mut self) {
+        let Some(file_diff) = &self.diff_model else {
+            self.status_message =
+                Some("Folding is only available for the built-in diff".to_string());
+            return;
+        };
+        let count = file_diff.hunks.len();
+        if self.diff_folded.len() < count {
+            self.diff_folded = (0..count).collect();
+        } else {
+            self.diff_folded.clear();
+        }
+        // Keep the cursor's hunk at the same height; the file header stays put
+        let hunk = self
+            .diff_rows
+            .get(self.diff_cursor)
+            .and_then(|row| row.hunk);
+        let offset = self.diff_cursor.saturating_sub(self.diff_scroll as usize);
+        self.render_model();
+        if let Some(row) = hunk.and_then(|hunk| self.hunk_row(hunk)) {
+            self.diff_cursor = row;
+            self.diff_scroll = row.saturating_sub(offset) as u16;
+        }
+    }
+
+    /// Show more unchanged lines above (or below) the hunk under the cursor. They
+    /// come from the new version of the file, so nothing is diffed again; hunks that meet
+    /// merge. A file that changed since it was diffed needs refreshing first.
+    fn expand_hunk(&mut self, above: bool) {
+        if self.diff_model.is_none() {
+            self.status_message =
+                Some("Expanding context is only available for the built-in diff".to_string());
+            return;
+        }
+        let (Some(path), Some(hunk)) = (self.selected_file.clone(), self.current_hunk()) else {
+            return;
+        };
+        // The lines after a cut hunk were dropped, not unchanged
+        let hunk_count = self.diff_model.as_ref().map_or(0, |d| d.hunks.len());
+        if !above && self.diff_truncated && hunk + 1 == hunk_count {
+            self.status_message = Some("The diff is truncated here | L: load full".to_string());
+            return;
+        }
+        let version = self.repository.file_version(&path);
+        if self.diff_version.as_ref() != Some(&version) {
+            self.status_message =
+                Some("The file changed since it was diffed | r: refresh".to_string());
+            return;
+        }
+        let content = match self.repository.new_content(&path, &version) {
+            Ok(content) => content,
+            Err(e) => {
+                self.status_message = Some(format!("{e:#}"));
+                return;
+            }
+        };
+        let text = String::from_utf8_lossy(&content);
+        let new_lines: Vec<&str> = match text.strip_suffix('\n').unwrap_or(&text) {
+            "" => Vec::new(),
+            text => text.split('\n').collect(),
+        };
+
+        let Some(file_diff) = self.diff_model.as_mut() else {
+            return;
+        };
+        let hunk_count = file_diff.hunks.len();
+        let expanded = if above {
+            file_diff.expand_above(hunk, EXPAND_STEP, &new_lines)
+        } else {
+            file_diff.expand_below(hunk, EXPAND_STEP, &new_lines)
+        };
+        let Some((expanded, first_added)) = expanded else {
+            let side = if above { "above" } else { "below" };
+            self.status_message = Some(format!("No more lines {side} this hunk"));
+            return;
+        };
+
+        // A merge removes the hunk after the expanded one, shifting the rest up
+        let removed = (file_diff.hunks.len() < hunk_count).then_some(expanded + 1);
+        self.diff_folded = self
+            .diff_folded
+            .iter()
+            .filter(|&&idx| idx != expanded && Some(idx) != removed)
+            .map(|&idx| match removed {
+                Some(removed) if idx > removed => idx - 1,
+                _ => idx,
+            })
+            .collect();
+        self.diff_content = file_diff.to_patch();
+        self.diff_highlights = self.diff_model.as_ref().and_then(|d| self.highlight(d));
+        self.render_model();
+
+        if above {
+            // Put the added lines right below the top row
+            let target = RowSource {
+                new_lineno: Some(first_added),
+                ..RowSource::default()
+            };
+            if let Some(row) = self.reveal_line(&target) {
+                self.diff_scroll = row.saturating_sub(1) as u16;
+                self.diff_cursor = row;
+            }
+        }
+    }
+
+    /// Scroll to the start of the next (or previous) hunk. Output from other tools
+    /// is searched for `@@` lines instead.
+    fn step_hunk(&mut self, forward: bool) {
+        let starts: Vec<usize> = if self.diff_model.is_none() {
+            self.diff_lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| {
+                    line.spans
+                        .iter()
+                        .map(|span| span.content.as_ref())
+                        .collect::<String>()
+                        .starts_with("@@")
+                })
+                .map(|(idx, _)| idx)
+                .collect()
+        } else {
+            self.diff_rows
+                .iter()
+                .enumerate()
+                .filter(|&(idx, row)| {
+                    row.hunk.is_some() && (idx == 0 || self.diff_rows[idx - 1].hunk != row.hunk)
+                })
+                .map(|(idx, _)| idx)
+                .collect()
+        };
+        let top = self.diff_scroll as usize;
+        let target = if forward {
+            starts.into_iter().find(|&row| row > top)
+        } else {
+            starts.into_iter().rev().find(|&row| row < top)
+        };
+        if let Some(row) = target {
+            self.diff_scroll = row as u16;
+            self.diff_cursor = row;
+        }
+    }
+
+    /// Show the diff of `path`, clearing the search if it hides the file.
+    /// Returns whether the file is in the list at all.
+    fn show_file(&mut self, path: &str) -> bool {
+        let Some(file_idx) = self.file_paths.iter().position(|p| p == path) else {
+            return false;
+        };
+        if !self.filtered_indices.contains(&file_idx) {
+            self.search_query.clear();
+            self.update_filter();
+        }
+        let Some(list_idx) = self
+            .filtered_indices
+            .iter()
+            .position(|&idx| idx == file_idx)
+        else {
+            return false;
+        };
+        self.list_state.select(Some(list_idx));
+        self.load_diff(false);
+        true
+    }
+
+    /// Line number gutter to draw, when enabled and there are numbers to show
+    fn gutter(&self) -> Option<Gutter> {
+        // Split columns carry their own line numbers
+        if !self.diff_line_numbers || self.split_model() {
+            return None;
+        }
+        Gutter::new(&self.diff_rows)
+    }
+
+    /// Whether the built-in diff is shown in the split layout
+    fn split_model(&self) -> bool {
+        self.diff_model.is_some() && self.diff_layout == DiffLayout::SideBySide
+    }
+
+    /// Switch the built-in diff to `layout`, or back to unified
+    fn toggle_layout(&mut self, layout: DiffLayout) {
+        if self.diff_model.is_none() {
+            let name = match layout {
+                DiffLayout::SideBySide => "Side-by-side view",
+                DiffLayout::WordDiff => "Word diff",
+                DiffLayout::Unified => "Unified view",
+            };
+            self.status_message = Some(format!("{name} is only available for the built-in diff"));
+            return;
+        }
+        self.diff_layout = self.diff_layout.toggle(layout);
+        self.diff_width = None;
+        let anchor = self.scroll_anchor();
+        self.render_model();
+        self.restore_scroll(&anchor);
+    }
+
+    fn selected_is_submodule(&self) -> bool {
+        self.selected_file.as_ref().is_some_and(|path| {
+            self.files
+                .iter()
+                .any(|f| &f.path == path && f.status == FileStatus::Submodule)
+        })
+    }
+
+    /// Replace the file list with the changes inside the selected submodule
+    fn enter_submodule(&mut self) {
+        let Some(path) = self.selected_file.clone() else {
+            return;
+        };
+        let opened = self
+            .repository
+            .open_submodule(&path)
+            .and_then(|repo| repo.get_changed_files().map(|files| (repo, files)));
+        let (repository, files) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                self.status_message = Some(format!("{e:#}"));
+                return;
+            }
+        };
+        let parent = std::mem::replace(&mut self.repository, repository);
+        self.parent_repos.push((parent, path));
+        // Cache keys are paths relative to the repository
+        self.diff_cache.clear();
+        self.moved_lines = None;
+        self.moved_search = None;
+        self.marked_files.clear();
+        self.marked_hunks.clear();
+        self.prefetcher.cancel_all();
+        self.diff_styles = DiffStyles::from_settings(&self.repository.diff_settings());
+        self.set_files(files, None);
+        self.status_message = None;
+        self.screen = Screen::FileList;
+        self.diff_scroll = 0;
+    }
+
+    /// Return to the parent repository, reselecting the submodule we came from
+    fn leave_submodule(&mut self) {
+        let Some((parent, path)) = self.parent_repos.pop() else {
+            return;
+        };
+        self.repository = parent;
+        self.diff_cache.clear();
+        self.moved_lines = None;
+        self.moved_search = None;
+        self.marked_files.clear();
+        self.marked_hunks.clear();
+        self.prefetcher.cancel_all();
+        self.diff_styles = DiffStyles::from_settings(&self.repository.diff_settings());
+        let files = self.repository.get_changed_files().unwrap_or_default();
+        self.set_files(files, Some(&path));
+        self.selected_file = Some(path);
+    }
+
+    fn set_files(&mut self, files: Vec<ChangedFile>, select: Option<&str>) {
+        self.file_paths = files.iter().map(|f| f.path.clone()).collect();
+        self.files = files;
+        self.search_query.clear();
+        self.filtered_indices = (0..self.files.len()).collect();
+        let selected = select
+            .and_then(|path| self.file_paths.iter().position(|p| p == path))
+            .or(if self.files.is_empty() { None } else { Some(0) });
+        self.list_state.select(selected);
+    }
+
     fn open_selected_in_editor(&mut self) {
         if let Some(list_idx) = self.list_state.selected() {
             if let Some(&file_idx) = self.filtered_indices.get(list_idx) {
@@ -385,6 +2237,11 @@ impl App {
         if let Some(ref file_path) = self.selected_file {
             let editor_config = &self.config.editor;
             let command = editor_config.get_command();
+            // The file list has no cursor; open at the top
+            let line = match self.screen {
+                Screen::DiffView => self.new_lineno_at(*self.selected_rows().start()),
+                _ => None,
+            };
 
             // Temporarily exit TUI mode
             let _ = terminal::disable_raw_mode();
@@ -392,8 +2249,12 @@ impl App {
 
             // Build and run the editor command
             let mut cmd = std::process::Command::new(&command);
+            // Status paths are relative to the workdir root, not to where we were launched
+            let workdir = self.repository.workdir();
+            cmd.current_dir(workdir);
             cmd.args(&editor_config.args);
-            cmd.arg(file_path);
+            let path = workdir.join(file_path);
+            cmd.args(editor_config.file_args(&command, &path.to_string_lossy(), line));
             let _ = cmd.status();
 
             // Restore TUI mode
@@ -408,3 +2269,78 @@ impl App {
         }
     }
 }
+
+/// Width available to the diff inside its borders
+fn diff_width() -> u16 {
+    terminal::size()
+        .map(|(w, _)| w.saturating_sub(2))
+        .unwrap_or(80)
+}
+
+/// Lines available inside the diff border, above the status line
+fn diff_height() -> u16 {
+    terminal::size()
+        .map(|(_, h)| h.saturating_sub(3))
+        .unwrap_or(21)
+}
+
+/// Hunks of a file marked for export
+pub struct HunkMarks {
+    /// Version of the file the hunks were marked in
+    version: FileVersion,
+    /// Old-side start and line count of each marked hunk of the diff without overrides
+    ranges: BTreeSet<(u32, u32)>,
+}
+
+/// Whether the hunk with the old-side range `(start, count)` lies within `hunk`
+fn contains_old_range(hunk: &Hunk, (start, count): (u32, u32)) -> bool {
+    let (before, after) = if count == 0 {
+        (start, start + 1)
+    } else {
+        (start - 1, start + count)
+    };
+    hunk.before().0 <= before && after <= hunk.after().0
+}
+
+/// Position in a diff that survives reformatting for a different width
+struct ScrollAnchor {
+    /// Normalized text at the start of the top visible line
+    key: String,
+    /// Relative position of the top visible line, used when the text can't be found
+    ratio: f64,
+    /// Normalized text of the line under the cursor
+    cursor_key: String,
+    /// Line numbers of the line under the cursor
+    cursor_row: RowSource,
+    /// Rows between the top of the view and the cursor
+    cursor_offset: usize,
+}
+
+/// Alphanumeric prefix of a line, ignoring the decorations and padding that
+/// change with the width (box drawing, line number columns, wrapping markers)
+fn anchor_key(line: &Line) -> String {
+    line.spans
+        .iter()
+        .flat_map(|span| span.content.chars())
+        .filter(|c| c.is_alphanumeric())
+        .take(40)
+        .collect()
+}
+
+/// Plain text of diff lines as shown, one per line
+fn lines_text(lines: &[Line]) -> String {
+    let mut out = String::new();
+    for line in lines {
+        out.extend(line.spans.iter().map(|span| span.content.as_ref()));
+        out.push('\n');
+    }
+    out
+}
+
+/// Whether two anchor keys could belong to the same line, allowing for a split row
+/// that holds the text of both sides
+fn same_text(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let start: String = short.chars().take(12).collect();
+    long.contains(&start)
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use crate::git::{DiffOverrides, DiffResult, FileVersion};
+
+/// Everything that determines the output of `get_diff` for a file
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DiffKey {
+    pub path: String,
+    pub version: FileVersion,
+    pub tool: String,
+    pub args: Vec<String>,
+    /// Only set for output that was formatted for a specific width
+    pub width: Option<u16>,
+    /// Only set for output that was formatted for a specific height
+    pub height: Option<u16>,
+    pub load_full: bool,
+    pub overrides: DiffOverrides,
+}
+
+struct Entry {
+    result: DiffResult,
+    size: usize,
+    last_used: u64,
+}
+
+/// Rendered diffs, evicted least recently used first once `max_bytes` is exceeded
+pub struct DiffCache {
+    entries: HashMap<DiffKey, Entry>,
+    max_bytes: usize,
+    total_bytes: usize,
+    clock: u64,
+}
+
+impl DiffCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            max_bytes,
+            total_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// Look up a diff, preferring size-independent output over output for `width` and
+    /// `height`. `key.width` and `key.height` are ignored.
+    pub fn get(&mut self, key: &DiffKey, width: u16, height: u16) -> Option<DiffResult> {
+        self.clock += 1;
+        let mut key = key.clone();
+        for (candidate_width, candidate_height) in [
+            (None, None),
+            (Some(width), None),
+            (None, Some(height)),
+            (Some(width), Some(height)),
+        ] {
+            key.width = candidate_width;
+            key.height = candidate_height;
+            if let Some(entry) = self.entries.get_mut(&key) {
+                entry.last_used = self.clock;
+                return Some(entry.result.clone());
+            }
+        }
+        None
+    }
+
+    /// Store a diff. `key.width` and `key.height` are taken from the result. Output shown in place of a
+    /// failed tool isn't stored, so the tool is tried again next time.
+    pub fn insert(&mut self, key: &DiffKey, result: &DiffResult) {
+        let size = result.size();
+        if size > self.max_bytes || result.fallback.is_some() {
+            return;
+        }
+
+        self.clock += 1;
+        let mut key = key.clone();
+        key.width = result.width;
+        key.height = result.height;
+        let entry = Entry {
+            result: result.clone(),
+            size,
+            last_used: self.clock,
+        };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.total_bytes -= old.size;
+        }
+        self.total_bytes += size;
+
+        while self.total_bytes > self.max_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    /// Drop entries whose file no longer has the given version. Paths that
+    /// `current_version` returns `None` for (no longer changed) are dropped too.
+    pub fn invalidate(&mut self, mut current_version: impl FnMut(&str) -> Option<FileVersion>) {
+        let mut versions: HashMap<String, Option<FileVersion>> = HashMap::new();
+        let stale: Vec<DiffKey> = self
+            .entries
+            .keys()
+            .filter(|key| {
+                let version = versions
+                    .entry(key.path.clone())
+                    .or_insert_with(|| current_version(&key.path));
+                version.as_ref() != Some(&key.version)
+            })
+            .cloned()
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.total_bytes = 0;
+    }
+
+    fn remove(&mut self, key: &DiffKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total_bytes -= entry.size;
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::ClipboardConfig;
+
+/// Longest encoded text sent with OSC 52; terminals ignore or cut off longer sequences
+const MAX_OSC52_BYTES: usize = 100_000;
+
+/// How OSC 52 reaches the outer terminal
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Osc52Route {
+    /// Written as is, to the terminal or to tmux with `set-clipboard on`
+    Direct,
+    /// Wrapped for tmux to pass through (`allow-passthrough on`)
+    Passthrough,
+    /// Handed to `tmux load-buffer -w`, which sends it itself (`set-clipboard external`)
+    TmuxBuffer,
+}
+
+/// Put `text` on the system clipboard: through the terminal with OSC 52, or with the
+/// configured command when OSC 52 is off, can't get out of tmux or the text is too
+/// large for it
+pub fn copy(text: &str, config: &ClipboardConfig) -> Result<()> {
+    let encoded = base64(text.as_bytes());
+    if config.osc52 && encoded.len() <= MAX_OSC52_BYTES {
+        match osc52_route() {
+            Some(Osc52Route::TmuxBuffer) => {
+                let command = ["tmux", "load-buffer", "-w", "-"].map(String::from);
+                return run_command(&command, text);
+            }
+            Some(route) => return write_osc52(&encoded, route == Osc52Route::Passthrough),
+            None if config.command.is_none() => {
+                bail!("tmux drops OSC 52; set -g set-clipboard on, or set clipboard.command")
+            }
+            None => {}
+        }
+    }
+    match &config.command {
+        Some(command) => run_command(command, text),
+        None if config.osc52 => bail!(
+            "Too large to copy through the terminal ({} bytes); set clipboard.command",
+            text.len()
+        ),
+        None => bail!("OSC 52 is off and no clipboard.command is set"),
+    }
+}
+
+/// The way OSC 52 gets out of tmux, if it does
+fn osc52_route() -> Option<Osc52Route> {
+    if env::var_os("TMUX").is_none() {
+        return Some(Osc52Route::Direct);
+    }
+    match tmux_option("set-clipboard").as_deref() {
+        Some("on") => Some(Osc52Route::Direct),
+        Some("external") => Some(Osc52Route::TmuxBuffer),
+        _ if matches!(
+            tmux_option("allow-passthrough").as_deref(),
+            Some("on" | "all")
+        ) =>
+        {
+            Some(Osc52Route::Passthrough)
+        }
+        _ => None,
+    }
+}
+
+fn write_osc52(encoded: &str, wrap: bool) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(osc52_sequence(encoded, wrap).as_bytes())?;
+    stdout.flush()?;
+    Ok(())
+}
+
+/// OSC 52 sequence setting the clipboard to `encoded`. Wrapped, tmux passes it on to
+/// the outer terminal when `allow-passthrough` is on.
+fn osc52_sequence(encoded: &str, wrap: bool) -> String {
+    let sequence = format!("\x1b]52;c;{encoded}\x07");
+    if wrap {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else {
+        sequence
+    }
+}
+
+/// Global value of a tmux option, if tmux knows it
+fn tmux_option(name: &str) -> Option<String> {
+    let output = Command::new("tmux")
+        .args(["show-options", "-gv", name])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn run_command(command: &[String], text: &str) -> Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("clipboard.command is empty");
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to run '{program}'"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(text.as_bytes())
+            .with_context(|| format!("Failed to write to '{program}'"))?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("'{program}' failed with {status}");
+    }
+    Ok(())
+}
+
+/// Standard base64 with padding, as OSC 52 expects
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let byte = |idx: usize| u32::from(chunk.get(idx).copied().unwrap_or(0));
+        let group = byte(0) << 16 | byte(1) << 8 | byte(2);
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * idx) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_partial_groups() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+    }
+
+    #[test]
+    fn osc52_wraps_for_tmux_only_when_asked() {
+        assert_eq!(osc52_sequence("Zm9v", false), "\x1b]52;c;Zm9v\x07");
+        assert_eq!(
+            osc52_sequence("Zm9v", true),
+            "\x1bPtmux;\x1b\x1b]52;c;Zm9v\x07\x1b\\"
+        );
+    }
+}
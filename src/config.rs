@@ -1,13 +1,20 @@
 use directories::ProjectDirs;
+use globset::{Glob, GlobMatcher};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::git::DiffAlgorithm;
+use crate::highlight;
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct Config {
     pub diff: DiffConfig,
     pub editor: EditorConfig,
+    pub clipboard: ClipboardConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,6 +25,32 @@ pub struct DiffConfig {
     pub tool: String,
     /// Additional arguments to pass to the diff tool
     pub args: Vec<String>,
+    /// Tools for specific files, checked in order before falling back to `tool`/`args`
+    pub rules: Vec<DiffRule>,
+    /// Kill an external diff tool that runs longer than this (0 disables the timeout)
+    pub timeout_ms: u64,
+    /// Stop reading diff output beyond this many bytes (0 disables the limit)
+    pub max_output_bytes: usize,
+    /// Per-tool overrides, keyed by command name (e.g. `[diff.tools.delta]`)
+    pub tools: HashMap<String, ToolConfig>,
+    /// Memory budget for rendered diffs kept around for quick re-display (0 disables caching)
+    pub cache_max_bytes: usize,
+    /// Compute the diffs of the next and previous files in the background
+    pub prefetch: bool,
+    /// Skip prefetching files larger than this many bytes
+    pub prefetch_max_file_bytes: u64,
+    /// Diff algorithm, overriding git's `diff.algorithm`
+    pub algorithm: Option<DiffAlgorithm>,
+    /// Start with the built-in diff split into old and new columns
+    pub side_by_side: bool,
+    /// Color the text of the built-in diff by language (needs the `syntax` feature)
+    pub syntax_highlight: bool,
+    /// Bundled syntax theme, e.g. "base16-ocean.dark" or "InspiredGitHub"
+    pub syntax_theme: String,
+    /// Color lines moved within the changeset in the built-in diff
+    pub color_moved: bool,
+    /// Start with old and new line numbers shown left of the diff
+    pub line_numbers: bool,
 }
 
 impl Default for DiffConfig {
@@ -25,10 +58,145 @@ impl Default for DiffConfig {
         Self {
             tool: "auto".to_string(),
             args: Vec::new(),
+            rules: Vec::new(),
+            timeout_ms: 5000,
+            max_output_bytes: 8 * 1024 * 1024,
+            tools: HashMap::new(),
+            cache_max_bytes: 64 * 1024 * 1024,
+            prefetch: true,
+            prefetch_max_file_bytes: 1024 * 1024,
+            algorithm: None,
+            side_by_side: false,
+            syntax_highlight: true,
+            syntax_theme: highlight::DEFAULT_THEME.to_string(),
+            color_moved: true,
+            line_numbers: false,
+        }
+    }
+}
+
+impl DiffConfig {
+    /// Tool and arguments for `path`: the first matching rule, or the default `tool`/`args`
+    pub fn tool_for(&self, path: &str) -> (&str, &[String]) {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map(|rule| (rule.tool.as_str(), rule.args.as_slice()))
+            .unwrap_or((self.tool.as_str(), self.args.as_slice()))
+    }
+
+    /// Settings for the given tool: `[diff.tools.<name>]` layered over the built-in defaults
+    pub fn tool_config(&self, tool: &str) -> ToolConfig {
+        let builtin = ToolConfig::builtin(tool);
+        match self.tools.get(tool) {
+            Some(configured) => ToolConfig {
+                command: configured.command.clone().or(builtin.command),
+                input: configured.input.or(builtin.input),
+                timeout_ms: configured.timeout_ms.or(builtin.timeout_ms),
+                max_output_bytes: configured.max_output_bytes.or(builtin.max_output_bytes),
+            },
+            None => builtin,
+        }
+    }
+
+    /// Timeout and output limits for the given tool, applying `[diff.tools.<name>]` overrides
+    pub fn limits_for(&self, tool: &str) -> DiffLimits {
+        let tool_config = self.tool_config(tool);
+        let timeout_ms = tool_config.timeout_ms.unwrap_or(self.timeout_ms);
+        let max_output_bytes = tool_config
+            .max_output_bytes
+            .unwrap_or(self.max_output_bytes);
+
+        DiffLimits {
+            timeout: (timeout_ms > 0).then(|| Duration::from_millis(timeout_ms)),
+            max_output_bytes: (max_output_bytes > 0).then_some(max_output_bytes),
+        }
+    }
+}
+
+/// `[[diff.rules]]` entry selecting a tool by file name
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiffRule {
+    /// Glob such as `"*.json"`. Patterns without a `/` match the file name in any
+    /// directory; patterns with one match the path from the repository root.
+    pub pattern: String,
+    pub tool: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// `pattern` compiled when the config is loaded; `None` if it is invalid
+    #[serde(skip)]
+    matcher: Option<GlobMatcher>,
+}
+
+impl DiffRule {
+    pub fn matches(&self, path: &str) -> bool {
+        let Some(matcher) = &self.matcher else {
+            return false;
+        };
+        if self.pattern.contains('/') {
+            matcher.is_match(path)
+        } else {
+            matcher.is_match(path.rsplit('/').next().unwrap_or(path))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ToolConfig {
+    /// Executable to run, when it differs from the tool name (e.g. `difft` for difftastic)
+    pub command: Option<String>,
+    /// How the tool expects to receive the change
+    pub input: Option<ToolInput>,
+    /// Overrides `diff.timeout_ms` for this tool
+    pub timeout_ms: Option<u64>,
+    /// Overrides `diff.max_output_bytes` for this tool
+    pub max_output_bytes: Option<usize>,
+}
+
+impl ToolConfig {
+    /// Defaults for tools that need more than a patch on stdin
+    fn builtin(tool: &str) -> Self {
+        match tool {
+            "difftastic" | "difft" => Self {
+                command: Some("difft".to_string()),
+                input: Some(ToolInput::ExternalDiff),
+                ..Self::default()
+            },
+            _ => Self::default(),
         }
     }
 }
 
+/// How an external tool receives the change
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ToolInput {
+    /// Unified patch on stdin (delta, diff-so-fancy, colordiff)
+    #[default]
+    Patch,
+    /// Old and new file paths appended to the arguments
+    Files,
+    /// `GIT_EXTERNAL_DIFF` arguments: path, old file, old hex, old mode, new file,
+    /// new hex, new mode (difftastic)
+    ExternalDiff,
+}
+
+/// Resource limits applied while producing a diff
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffLimits {
+    pub timeout: Option<Duration>,
+    pub max_output_bytes: Option<usize>,
+}
+
+impl DiffLimits {
+    /// No timeout and no output limit, for when the user asks for the full output anyway
+    pub const UNLIMITED: Self = Self {
+        timeout: None,
+        max_output_bytes: None,
+    };
+}
+
 #[derive(Debug, Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct EditorConfig {
@@ -36,6 +204,10 @@ pub struct EditorConfig {
     pub command: Option<String>,
     /// Additional arguments to pass to the editor
     pub args: Vec<String>,
+    /// Arguments that open the file at a line, in place of the file path, with
+    /// `{path}`, `{line}` and `{col}` placeholders, e.g. `["+{line}", "{path}"]`.
+    /// Well-known editors have a preset.
+    pub goto: Option<Vec<String>>,
 }
 
 impl EditorConfig {
@@ -46,6 +218,69 @@ impl EditorConfig {
             .or_else(|| std::env::var("EDITOR").ok())
             .unwrap_or_else(|| "vi".to_string())
     }
+
+    /// Arguments that open `path` at `line` with `command`: the `goto` template, or
+    /// the preset for the editor. Just the path without a line or for editors we don't know.
+    pub fn file_args(&self, command: &str, path: &str, line: Option<u32>) -> Vec<String> {
+        let Some(line) = line else {
+            return vec![path.to_string()];
+        };
+        let template: Vec<&str> = match &self.goto {
+            Some(goto) => goto.iter().map(String::as_str).collect(),
+            None => goto_preset(command).to_vec(),
+        };
+        let line = line.to_string();
+        template
+            .iter()
+            .map(|arg| {
+                arg.replace("{path}", path)
+                    .replace("{line}", &line)
+                    .replace("{col}", "1")
+            })
+            .collect()
+    }
+}
+
+/// How common editors are told to open a file at a line, recognized by the
+/// program name in `command`
+fn goto_preset(command: &str) -> &'static [&'static str] {
+    let name = command
+        .split_whitespace()
+        .next()
+        .and_then(|program| Path::new(program).file_stem())
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    match name {
+        "vi" | "vim" | "nvim" | "gvim" | "mvim" | "view" | "nano" | "pico" | "emacs"
+        | "emacsclient" | "micro" | "kak" | "joe" | "mg" | "ne" | "gedit" => &["+{line}", "{path}"],
+        "code" | "code-insiders" | "codium" | "cursor" | "windsurf" => {
+            &["--goto", "{path}:{line}:{col}"]
+        }
+        "hx" | "helix" | "subl" | "zed" => &["{path}:{line}:{col}"],
+        "kate" | "idea" | "pycharm" | "clion" | "goland" | "webstorm" | "rustrover" => {
+            &["--line", "{line}", "{path}"]
+        }
+        _ => &["{path}"],
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    /// Copy through the terminal with an OSC 52 escape sequence, which also works over SSH
+    pub osc52: bool,
+    /// Command that puts its stdin on the clipboard, e.g. `["pbcopy"]`, used when
+    /// `osc52` is off or the text is too large for it
+    pub command: Option<Vec<String>>,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            osc52: true,
+            command: None,
+        }
+    }
 }
 
 impl Config {
@@ -69,8 +304,24 @@ impl Config {
             return Err(ConfigError::NotFound);
         }
 
-        let content = fs::read_to_string(&path)?;
-        let config: Config = toml::from_str(&content)?;
+        Self::parse(&fs::read_to_string(&path)?)
+    }
+
+    /// Parse the contents of a config file, compiling the diff rules
+    fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut config: Config = toml::from_str(content)?;
+        for rule in &mut config.diff.rules {
+            match Glob::new(&rule.pattern) {
+                Ok(glob) => rule.matcher = Some(glob.compile_matcher()),
+                Err(e) => eprintln!("Warning: Ignoring diff rule '{}': {e}", rule.pattern),
+            }
+        }
+        if config.diff.syntax_highlight && !highlight::theme_exists(&config.diff.syntax_theme) {
+            eprintln!(
+                "Warning: Unknown syntax theme '{}'; syntax highlighting is disabled",
+                config.diff.syntax_theme
+            );
+        }
         Ok(config)
     }
 
@@ -108,3 +359,83 @@ impl From<toml::de::Error> for ConfigError {
         ConfigError::Parse(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_rules_match_in_order() {
+        let config = Config::parse(
+            r#"
+            [diff]
+            tool = "delta"
+
+            [[diff.rules]]
+            pattern = "*.json"
+            tool = "jd"
+
+            [[diff.rules]]
+            pattern = "docs/*.md"
+            tool = "mdiff"
+
+            [[diff.rules]]
+            pattern = "*.md"
+            tool = "glow"
+            args = ["--plain"]
+
+            [[diff.rules]]
+            pattern = "[invalid"
+            tool = "never"
+            "#,
+        )
+        .unwrap();
+
+        let cases = [
+            // Patterns without a `/` match the file name in any directory
+            ("a.json", "jd"),
+            ("src/nested/a.json", "jd"),
+            // Patterns with one match the path from the repository root
+            ("docs/guide.md", "mdiff"),
+            ("src/docs/guide.md", "glow"),
+            // The first matching rule wins
+            ("README.md", "glow"),
+            ("a.jsonl", "delta"),
+            ("[invalid", "delta"),
+        ];
+        for (path, tool) in cases {
+            assert_eq!(config.diff.tool_for(path).0, tool, "{path}");
+        }
+        assert_eq!(config.diff.tool_for("README.md").1, ["--plain"]);
+        assert!(config.diff.tool_for("a.txt").1.is_empty());
+    }
+
+    #[test]
+    fn editor_file_args_use_the_goto_template_or_preset() {
+        let preset = EditorConfig::default();
+        let cases = [
+            ("code --wait", vec!["--goto", "src/a.rs:12:1"]),
+            ("/usr/bin/nvim", vec!["+12", "src/a.rs"]),
+            ("idea", vec!["--line", "12", "src/a.rs"]),
+            ("unknown-editor -w", vec!["src/a.rs"]),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(
+                preset.file_args(command, "src/a.rs", Some(12)),
+                expected,
+                "{command}"
+            );
+        }
+        // Without a line, only the path is passed
+        assert_eq!(preset.file_args("nvim", "src/a.rs", None), ["src/a.rs"]);
+
+        let custom = EditorConfig {
+            goto: Some(vec!["-l".into(), "{line}:{col}".into(), "{path}".into()]),
+            ..EditorConfig::default()
+        };
+        assert_eq!(
+            custom.file_args("nvim", "src/a.rs", Some(12)),
+            ["-l", "12:1", "src/a.rs"]
+        );
+    }
+}
@@ -36,7 +36,7 @@ impl FuzzyMatcher {
             .collect();
 
         // Sort by score descending
-        matches.sort_by(|a, b| b.1.cmp(&a.1));
+        matches.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
 
         matches.into_iter().map(|(idx, _)| idx).collect()
     }
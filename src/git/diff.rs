@@ -1,121 +1,743 @@
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::ffi::OsString;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::config::DiffConfig;
+use tempfile::NamedTempFile;
+use thiserror::Error;
 
-pub fn get_diff(file_path: &str, width: u16, config: &DiffConfig) -> Vec<u8> {
-    match config.tool.as_str() {
-        "auto" => {
-            // Try delta first, then fallback to git diff
-            if let Ok(output) = try_tool("delta", file_path, width, &["--width"]) {
-                return output;
+use super::{DiffAlgorithm, DiffOverrides, FileDiff, Repository};
+use crate::config::{DiffConfig, DiffLimits, ToolConfig, ToolInput};
+use crate::highlight::{self, DiffHighlights};
+
+/// Upper bound on captured stderr, which is only used for error messages
+const MAX_STDERR_BYTES: usize = 64 * 1024;
+
+/// How often a running tool checks for timeouts and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What to diff and how
+pub struct DiffRequest<'a> {
+    pub path: &'a str,
+    /// Width available for width-dependent formatters such as delta
+    pub width: u16,
+    /// Number of diff lines visible at once, for the `{height}` placeholder
+    pub height: u16,
+    /// Ignore the configured timeouts and output limits
+    pub load_full: bool,
+    /// Whitespace and context settings chosen in the diff view
+    pub overrides: DiffOverrides,
+    /// Set from another thread to abandon the diff, killing any external tool
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+/// Result of diffing a single file
+#[derive(Clone)]
+pub enum DiffOutput {
+    /// Diff generated in-process, rendered by the built-in renderer
+    Native(FileDiff),
+    /// ANSI-colored output of an external formatter
+    Formatted(Vec<u8>),
+}
+
+#[derive(Clone)]
+pub struct DiffResult {
+    pub output: DiffOutput,
+    /// Why the configured tool was not used, when we fell back to the built-in diff
+    pub fallback: Option<DiffError>,
+    /// Problems that didn't stop the diff from being shown
+    pub warnings: Vec<String>,
+    /// Set when only part of the output is shown because a limit was hit
+    pub truncated: Option<Truncation>,
+    /// Terminal width the output was formatted for, if it depends on it
+    pub width: Option<u16>,
+    /// Diff view height the output was formatted for, if it depends on it
+    pub height: Option<u16>,
+    /// Syntax colors for `DiffOutput::Native`, computed along with the diff so that
+    /// they are cached and prefetched with it
+    pub highlights: Option<DiffHighlights>,
+}
+
+/// Why a diff was cut short
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truncation {
+    Timeout(Duration),
+    OutputLimit(usize),
+}
+
+impl std::fmt::Display for Truncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Truncation::Timeout(timeout) => {
+                write!(f, "timed out after {}", format_duration(*timeout))
             }
-            try_git_diff(file_path).unwrap_or_else(|_| b"Failed to get diff".to_vec())
-        }
-        "git" => try_git_diff(file_path).unwrap_or_else(|_| b"Failed to get diff".to_vec()),
-        tool => {
-            // Try the specified tool
-            if let Ok(output) = try_tool(tool, file_path, width, &config.args) {
-                return output;
+            Truncation::OutputLimit(bytes) => {
+                write!(f, "output exceeded {}", format_size(*bytes))
             }
-            // Fallback to git diff
-            try_git_diff(file_path).unwrap_or_else(|_| b"Failed to get diff".to_vec())
         }
     }
 }
 
+#[derive(Debug, Clone, Error)]
+pub enum DiffError {
+    #[error("{tool}: command not found")]
+    ToolNotFound { tool: String },
+    #[error("{tool}: failed to run: {source}")]
+    Spawn {
+        tool: String,
+        #[source]
+        source: Arc<std::io::Error>,
+    },
+    #[error("{tool} exited with {}", exit_description(*.code))]
+    Exit {
+        tool: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    #[error("{tool} timed out after {}", format_duration(*.timeout))]
+    Timeout { tool: String, timeout: Duration },
+    #[error("{tool} produced no output")]
+    EmptyOutput { tool: String },
+    #[error("no changes")]
+    EmptyDiff,
+    #[error("cancelled")]
+    Cancelled,
+    #[error("failed to get diff: {0}")]
+    Git(String),
+}
+
+impl DiffResult {
+    /// Approximate memory used by the output, for cache accounting
+    pub fn size(&self) -> usize {
+        let output = match &self.output {
+            DiffOutput::Native(file_diff) => file_diff.size(),
+            DiffOutput::Formatted(content) => content.len(),
+        };
+        output + self.highlights.as_ref().map_or(0, DiffHighlights::size)
+    }
+}
+
+impl DiffError {
+    /// Name of the external tool that failed, if any
+    pub fn tool(&self) -> Option<&str> {
+        match self {
+            DiffError::ToolNotFound { tool }
+            | DiffError::Spawn { tool, .. }
+            | DiffError::Exit { tool, .. }
+            | DiffError::Timeout { tool, .. }
+            | DiffError::EmptyOutput { tool } => Some(tool),
+            DiffError::EmptyDiff | DiffError::Cancelled | DiffError::Git(_) => None,
+        }
+    }
+
+    /// Additional output worth showing to the user, such as the tool's stderr
+    pub fn details(&self) -> Option<&str> {
+        match self {
+            DiffError::Exit { stderr, .. } if !stderr.trim().is_empty() => Some(stderr.trim()),
+            _ => None,
+        }
+    }
+}
+
+fn exit_description(code: Option<i32>) -> String {
+    match code {
+        Some(code) => format!("status {code}"),
+        None => "a signal".to_string(),
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    const KIB: usize = 1024;
+    const MIB: usize = 1024 * KIB;
+    if bytes >= MIB && bytes % MIB == 0 {
+        format!("{} MiB", bytes / MIB)
+    } else if bytes >= KIB && bytes % KIB == 0 {
+        format!("{} KiB", bytes / KIB)
+    } else {
+        format!("{bytes} bytes")
+    }
+}
+
+/// Diff a single file
+pub fn get_diff(
+    repository: &Repository,
+    request: &DiffRequest,
+    config: &DiffConfig,
+) -> Result<DiffResult, DiffError> {
+    let limits = |tool: &str| {
+        if request.load_full {
+            DiffLimits::UNLIMITED
+        } else {
+            config.limits_for(tool)
+        }
+    };
+
+    let mut settings = repository.diff_settings();
+    if let Some(algorithm) = config.algorithm {
+        settings.algorithm = algorithm;
+    }
+    let settings = settings.with_overrides(&request.overrides);
+
+    let mut warnings = Vec::new();
+    let git_diff = match settings.algorithm {
+        DiffAlgorithm::Histogram => match repository.git_file_diff(request.path, &settings) {
+            Ok(Some(file_diff)) => Some(file_diff),
+            // `git diff HEAD` leaves untracked files out
+            Ok(None) => {
+                warnings.push(
+                    "Untracked file; using the patience algorithm instead of histogram".to_string(),
+                );
+                None
+            }
+            Err(e) => {
+                warnings.push(format!("{e:#}; using the patience algorithm instead"));
+                None
+            }
+        },
+        _ => None,
+    };
+    let mut file_diff = match git_diff {
+        Some(file_diff) => file_diff,
+        None => repository
+            .file_diff(request.path, &settings)
+            .map_err(|e| DiffError::Git(format!("{e:#}")))?,
+    };
+    if file_diff.is_empty() {
+        return Err(DiffError::EmptyDiff);
+    }
+
+    let (configured_tool, args) = config.tool_for(request.path);
+    let formatted = match configured_tool {
+        // Try delta first, then fallback to the built-in diff
+        "auto" => match try_tool(
+            repository,
+            request,
+            "delta",
+            &config.tool_config("delta"),
+            &file_diff,
+            &["--width"],
+            &limits("delta"),
+        ) {
+            // delta simply not being installed is the expected case for "auto"
+            Err(DiffError::ToolNotFound { .. }) => None,
+            result => Some(result),
+        },
+        "git" => None,
+        tool => Some(try_tool(
+            repository,
+            request,
+            tool,
+            &config.tool_config(tool),
+            &file_diff,
+            args,
+            &limits(tool),
+        )),
+    };
+
+    let fallback = match formatted {
+        Some(Ok(output)) => {
+            return Ok(DiffResult {
+                output: DiffOutput::Formatted(output.stdout),
+                fallback: None,
+                warnings,
+                truncated: output.truncated,
+                width: output.width_dependent.then_some(request.width),
+                height: output.height_dependent.then_some(request.height),
+                highlights: None,
+            })
+        }
+        Some(Err(DiffError::Cancelled)) => return Err(DiffError::Cancelled),
+        Some(Err(e)) => Some(e),
+        None => None,
+    };
+
+    let truncated = limits("git")
+        .max_output_bytes
+        .filter(|&max| file_diff.truncate(max))
+        .map(Truncation::OutputLimit);
+    // Git's color settings also decide whether syntax colors are used
+    let highlights = if config.syntax_highlight && settings.color {
+        highlight::highlight(request.path, &file_diff, &config.syntax_theme)
+    } else {
+        None
+    };
+    Ok(DiffResult {
+        output: DiffOutput::Native(file_diff),
+        fallback,
+        warnings,
+        truncated,
+        width: None,
+        height: None,
+        highlights,
+    })
+}
+
+struct ToolOutput {
+    stdout: Vec<u8>,
+    truncated: Option<Truncation>,
+    /// Whether the tool was told the terminal width
+    width_dependent: bool,
+    /// Whether the tool was told the diff view height
+    height_dependent: bool,
+}
+
 fn try_tool(
+    repository: &Repository,
+    request: &DiffRequest,
     tool_name: &str,
-    file_path: &str,
-    width: u16,
+    tool_config: &ToolConfig,
+    file_diff: &FileDiff,
     extra_args: &[impl AsRef<str>],
-) -> Result<Vec<u8>, ()> {
-    // Check if the tool is available
-    if which::which(tool_name).is_err() {
-        return Err(());
-    }
+    limits: &DiffLimits,
+) -> Result<ToolOutput, DiffError> {
+    let program = tool_config.command.as_deref().unwrap_or(tool_name);
 
-    // Get git diff first
-    let diff_input = get_git_diff_output(file_path)?;
-
-    if diff_input.is_empty() {
-        return Err(());
+    // Check if the tool is available
+    if which::which(program).is_err() {
+        return Err(DiffError::ToolNotFound {
+            tool: program.to_string(),
+        });
     }
 
     // Build command with arguments
-    let mut cmd = Command::new(tool_name);
+    let mut cmd = Command::new(program);
+    cmd.current_dir(repository.workdir());
 
-    // Add width argument for delta
-    if tool_name == "delta" {
-        cmd.args(["--width", &width.to_string()]);
-    }
+    // Tell width-aware tools how much room they have
+    let width = request.width.to_string();
+    let mut width_dependent = match program {
+        "delta" => {
+            cmd.args(["--width", &width]);
+            true
+        }
+        "difft" => {
+            cmd.env("DFT_WIDTH", &width).env("DFT_COLOR", "always");
+            true
+        }
+        _ => false,
+    };
+
+    let input = tool_config.input.unwrap_or_default();
+    let mentions_files = extra_args
+        .iter()
+        .any(|arg| FILE_PLACEHOLDERS.iter().any(|p| arg.as_ref().contains(p)));
+    let files = if input != ToolInput::Patch || mentions_files {
+        Some(
+            FilePair::new(repository, request.path).map_err(|source| DiffError::Spawn {
+                tool: program.to_string(),
+                source: Arc::new(source),
+            })?,
+        )
+    } else {
+        None
+    };
 
     // Add extra arguments from config
+    let height = request.height.to_string();
+    let mut height_dependent = false;
     for arg in extra_args {
         let arg_str = arg.as_ref();
         // Skip --width for delta as we already added it
-        if tool_name == "delta" && arg_str == "--width" {
+        if program == "delta" && arg_str == "--width" {
             continue;
         }
-        cmd.arg(arg_str);
+        width_dependent |= arg_str.contains("{width}");
+        height_dependent |= arg_str.contains("{height}");
+        cmd.arg(expand_placeholders(
+            arg_str,
+            &[
+                ("{width}", &width),
+                ("{height}", &height),
+                ("{path}", request.path),
+            ],
+            files.as_ref(),
+        ));
+    }
+
+    match &files {
+        None => {}
+        // File arguments placed explicitly with placeholders aren't appended again
+        Some(_) if mentions_files && input == ToolInput::Files => {}
+        Some(files) if input == ToolInput::Files => {
+            cmd.arg(&files.old.path).arg(&files.new.path);
+        }
+        Some(files) if input == ToolInput::ExternalDiff => {
+            cmd.arg(request.path);
+            for side in [&files.old, &files.new] {
+                cmd.arg(&side.path).arg(&side.hex).arg(&side.mode);
+            }
+        }
+        Some(_) => {}
     }
 
-    // Pipe diff through the tool
+    // Tools that read files get nothing on stdin, even when placeholders made us write them
+    let stdin = (input == ToolInput::Patch).then(|| file_diff.to_patch());
+    let mut output = run_tool(cmd, program, stdin, limits, request.cancel)?;
+    output.width_dependent = width_dependent;
+    output.height_dependent = height_dependent;
+    Ok(output)
+}
+
+/// Placeholders that need the old and new versions written to disk
+const FILE_PLACEHOLDERS: [&str; 4] = ["{old_file}", "{new_file}", "{old_rev}", "{new_rev}"];
+
+/// Replace `{width}`-style placeholders in a configured argument.
+/// Unknown placeholders are passed through unchanged.
+fn expand_placeholders(arg: &str, values: &[(&str, &str)], files: Option<&FilePair>) -> OsString {
+    let mut expanded = arg.to_string();
+    for (placeholder, value) in values {
+        expanded = expanded.replace(placeholder, value);
+    }
+    let Some(files) = files else {
+        return expanded.into();
+    };
+    expanded = expanded
+        .replace("{old_rev}", &files.old.hex)
+        .replace("{new_rev}", &files.new.hex);
+
+    // Paths may not be valid UTF-8, so splice them in as OS strings
+    let mut out = OsString::new();
+    let mut rest = expanded.as_str();
+    while let Some((pos, placeholder, side)) =
+        [("{old_file}", &files.old), ("{new_file}", &files.new)]
+            .into_iter()
+            .filter_map(|(placeholder, side)| {
+                rest.find(placeholder).map(|pos| (pos, placeholder, side))
+            })
+            .min_by_key(|&(pos, _, _)| pos)
+    {
+        out.push(&rest[..pos]);
+        out.push(side.path.as_os_str());
+        rest = &rest[pos + placeholder.len()..];
+    }
+    out.push(rest);
+    out
+}
+
+/// One side of a change as a file on disk, with its `GIT_EXTERNAL_DIFF` hex and mode
+struct FileSide {
+    path: PathBuf,
+    hex: String,
+    mode: String,
+    /// Keeps a materialized blob alive until the tool has run
+    _temp: Option<NamedTempFile>,
+}
+
+impl FileSide {
+    fn missing() -> Self {
+        Self {
+            path: PathBuf::from("/dev/null"),
+            hex: ".".to_string(),
+            mode: ".".to_string(),
+            _temp: None,
+        }
+    }
+}
+
+/// The `HEAD` and working tree versions of a file, for tools that compare files
+struct FilePair {
+    old: FileSide,
+    new: FileSide,
+}
+
+impl FilePair {
+    fn new(repository: &Repository, path: &str) -> std::io::Result<Self> {
+        let old = match repository.head_blob(path) {
+            Some(blob) => {
+                // Keep the extension so tools can still detect the language
+                let suffix = Path::new(path)
+                    .extension()
+                    .map(|ext| format!(".{}", ext.to_string_lossy()))
+                    .unwrap_or_default();
+                let mut temp = tempfile::Builder::new()
+                    .prefix("diff-tui-")
+                    .suffix(&suffix)
+                    .tempfile()?;
+                temp.write_all(&blob.content)?;
+                temp.flush()?;
+                FileSide {
+                    path: temp.path().to_path_buf(),
+                    hex: blob.id.to_string(),
+                    mode: format!("{:06o}", blob.mode),
+                    _temp: Some(temp),
+                }
+            }
+            None => FileSide::missing(),
+        };
+
+        let new_path = repository.workdir().join(path);
+        let new = match std::fs::symlink_metadata(&new_path) {
+            // Like git, working tree files are passed with the null object id
+            Ok(metadata) => FileSide {
+                path: new_path,
+                hex: "0".repeat(40),
+                mode: worktree_mode(&metadata).to_string(),
+                _temp: None,
+            },
+            Err(_) => FileSide::missing(),
+        };
+
+        Ok(Self { old, new })
+    }
+}
+
+fn worktree_mode(metadata: &std::fs::Metadata) -> &'static str {
+    if metadata.file_type().is_symlink() {
+        return "120000";
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 != 0 {
+            return "100755";
+        }
+    }
+    "100644"
+}
+
+/// Run the command, piping `input` to it if given, enforcing the timeout and output limit.
+/// Output that was read before a limit was hit is returned as truncated output.
+fn run_tool(
+    mut cmd: Command,
+    tool_name: &str,
+    input: Option<Vec<u8>>,
+    limits: &DiffLimits,
+    cancel: Option<&AtomicBool>,
+) -> Result<ToolOutput, DiffError> {
+    let spawn_error = |source| DiffError::Spawn {
+        tool: tool_name.to_string(),
+        source: Arc::new(source),
+    };
+
+    let stdin = match input {
+        Some(_) => Stdio::piped(),
+        None => Stdio::null(),
+    };
     let mut process = cmd
-        .stdin(Stdio::piped())
+        .stdin(stdin)
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
-        .map_err(|_| ())?;
+        .map_err(spawn_error)?;
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
 
-    if let Some(ref mut stdin) = process.stdin {
-        let _ = stdin.write_all(&diff_input);
+    // Feed stdin and drain stdout/stderr on separate threads so a tool that
+    // produces output before consuming all of its input can't deadlock us
+    if let (Some(mut stdin), Some(input)) = (process.stdin.take(), input) {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
     }
-    process.stdin.take();
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let (done_tx, done_rx) = mpsc::channel();
+    if let Some(pipe) = process.stdout.take() {
+        let stdout = Arc::clone(&stdout);
+        let max_bytes = limits.max_output_bytes;
+        thread::spawn(move || {
+            let exceeded = read_limited(pipe, &stdout, max_bytes);
+            let _ = done_tx.send(exceeded);
+        });
+    }
+    let stderr_reader = process.stderr.take().map(|pipe| {
+        thread::spawn(move || {
+            let stderr = Mutex::new(Vec::new());
+            read_limited(pipe, &stderr, Some(MAX_STDERR_BYTES));
+            stderr.into_inner().unwrap_or_else(|e| e.into_inner())
+        })
+    });
 
-    let output = process.wait_with_output().map_err(|_| ())?;
-    Ok(output.stdout)
-}
+    let truncated = loop {
+        match done_rx.recv_timeout(POLL_INTERVAL) {
+            Ok(false) | Err(mpsc::RecvTimeoutError::Disconnected) => break None,
+            Ok(true) => break limits.max_output_bytes.map(Truncation::OutputLimit),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if is_cancelled(cancel) {
+                    let _ = process.kill();
+                    let _ = process.wait();
+                    return Err(DiffError::Cancelled);
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    break limits.timeout.map(Truncation::Timeout);
+                }
+            }
+        }
+    };
 
-fn get_git_diff_output(file_path: &str) -> Result<Vec<u8>, ()> {
-    let output = Command::new("git")
-        .args(["diff", file_path])
-        .output()
-        .map_err(|_| ())?;
+    let status = match truncated {
+        Some(_) => {
+            let _ = process.kill();
+            let _ = process.wait();
+            None
+        }
+        None => match wait_until(&mut process, deadline, cancel).map_err(spawn_error)? {
+            WaitOutcome::Exited(status) => Some(status),
+            WaitOutcome::TimedOut => {
+                return Err(DiffError::Timeout {
+                    tool: tool_name.to_string(),
+                    timeout: limits.timeout.unwrap_or_default(),
+                })
+            }
+            WaitOutcome::Cancelled => return Err(DiffError::Cancelled),
+        },
+    };
 
-    if !output.stdout.is_empty() {
-        return Ok(output.stdout);
+    let mut stdout = std::mem::take(&mut *stdout.lock().unwrap_or_else(|e| e.into_inner()));
+    if let Some(status) = status {
+        if !status.success() {
+            let stderr = stderr_reader
+                .and_then(|reader| reader.join().ok())
+                .unwrap_or_default();
+            return Err(DiffError::Exit {
+                tool: tool_name.to_string(),
+                code: status.code(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            });
+        }
+    }
+
+    if truncated.is_some() {
+        // Don't leave half a line (or half an escape sequence) at the end
+        let end = stdout
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |pos| pos + 1);
+        stdout.truncate(end);
+    }
+    if stdout.is_empty() {
+        return Err(match truncated {
+            Some(Truncation::Timeout(timeout)) => DiffError::Timeout {
+                tool: tool_name.to_string(),
+                timeout,
+            },
+            _ => DiffError::EmptyOutput {
+                tool: tool_name.to_string(),
+            },
+        });
     }
+    Ok(ToolOutput {
+        stdout,
+        truncated,
+        width_dependent: false,
+        height_dependent: false,
+    })
+}
 
-    // Try for untracked/new files
-    let output = Command::new("git")
-        .args(["diff", "--no-index", "/dev/null", file_path])
-        .output()
-        .map_err(|_| ())?;
+/// Read `pipe` to the end into `buffer`, stopping once `max_bytes` would be exceeded.
+/// Returns whether the limit was hit.
+fn read_limited(mut pipe: impl Read, buffer: &Mutex<Vec<u8>>, max_bytes: Option<usize>) -> bool {
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let n = match pipe.read(&mut chunk) {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => n,
+        };
+        let mut buffer = buffer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(max_bytes) = max_bytes {
+            if buffer.len() + n > max_bytes {
+                let remaining = max_bytes - buffer.len();
+                buffer.extend_from_slice(&chunk[..remaining]);
+                return true;
+            }
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+}
 
-    Ok(output.stdout)
+fn is_cancelled(cancel: Option<&AtomicBool>) -> bool {
+    cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed))
 }
 
-fn try_git_diff(file_path: &str) -> Result<Vec<u8>, ()> {
-    let output = Command::new("git")
-        .args(["diff", "--color=always", file_path])
-        .output()
-        .map_err(|_| ())?;
+enum WaitOutcome {
+    Exited(std::process::ExitStatus),
+    TimedOut,
+    Cancelled,
+}
 
-    if !output.stdout.is_empty() {
-        return Ok(output.stdout);
+/// Wait for the process to exit, killing it once the deadline passes or it is cancelled
+fn wait_until(
+    process: &mut Child,
+    deadline: Option<Instant>,
+    cancel: Option<&AtomicBool>,
+) -> std::io::Result<WaitOutcome> {
+    if deadline.is_none() && cancel.is_none() {
+        return process.wait().map(WaitOutcome::Exited);
+    }
+    loop {
+        if let Some(status) = process.try_wait()? {
+            return Ok(WaitOutcome::Exited(status));
+        }
+        let outcome = if is_cancelled(cancel) {
+            WaitOutcome::Cancelled
+        } else if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            WaitOutcome::TimedOut
+        } else {
+            thread::sleep(Duration::from_millis(10));
+            continue;
+        };
+        let _ = process.kill();
+        let _ = process.wait();
+        return Ok(outcome);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Try for untracked/new files
-    let output = Command::new("git")
-        .args([
-            "diff",
-            "--color=always",
-            "--no-index",
-            "/dev/null",
-            file_path,
-        ])
-        .output()
-        .map_err(|_| ())?;
+    #[test]
+    fn placeholders_expand_to_request_values_and_files() {
+        let dir = crate::git::repo_with_files(&[("src/a.rs", "old\n")]);
+        std::fs::write(dir.path().join("src/a.rs"), "new\n").unwrap();
+        let repository = Repository::discover(dir.path()).unwrap();
+        let files = FilePair::new(&repository, "src/a.rs").unwrap();
+        let old_file = files.old.path.to_str().unwrap().to_string();
+        let new_file = repository.workdir().join("src/a.rs");
+        let new_file = new_file.to_str().unwrap().to_string();
+        let old_rev = repository.head_blob("src/a.rs").unwrap().id.to_string();
+        let new_rev = "0".repeat(40);
+        let values = [
+            ("{width}", "80"),
+            ("{height}", "24"),
+            ("{path}", "src/a.rs"),
+        ];
 
-    Ok(output.stdout)
+        let cases = [
+            ("--width={width}", "--width=80".to_string()),
+            ("{height}", "24".to_string()),
+            ("{path}", "src/a.rs".to_string()),
+            ("{old_file}", old_file.clone()),
+            ("{new_file}", new_file.clone()),
+            ("{new_file}:{old_file}", format!("{new_file}:{old_file}")),
+            // Working tree files have the null object id, as in GIT_EXTERNAL_DIFF
+            ("{old_rev}..{new_rev}", format!("{old_rev}..{new_rev}")),
+            ("{line}", "{line}".to_string()),
+            ("{width", "{width".to_string()),
+        ];
+        for (arg, expected) in cases {
+            assert_eq!(
+                expand_placeholders(arg, &values, Some(&files)),
+                OsString::from(&expected),
+                "{arg}"
+            );
+        }
+
+        // Without files, their placeholders are left alone
+        assert_eq!(
+            expand_placeholders("{path} {old_file} {new_rev}", &values, None),
+            "src/a.rs {old_file} {new_rev}"
+        );
+    }
 }
@@ -1,5 +1,12 @@
 mod diff;
+mod patch;
 mod repository;
 
-pub use diff::get_diff;
-pub use repository::{ChangedFile, FileStatus, Repository};
+pub use diff::{get_diff, DiffError, DiffOutput, DiffRequest, DiffResult};
+pub use patch::{
+    parse_hunk_ranges, retain_hunks, DiffAlgorithm, DiffLine, DiffOverrides, FileDiff,
+    GitDiffSettings, Hunk, LineKind,
+};
+#[cfg(test)]
+pub use repository::tests::repo_with_files;
+pub use repository::{ChangedFile, FileStatus, FileVersion, Repository};
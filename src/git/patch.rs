@@ -0,0 +1,1100 @@
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use git2::{DiffFormat, DiffOptions, Repository as Git2Repository};
+use serde::Deserialize;
+
+/// Diff algorithm, as configured by `diff.algorithm`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffAlgorithm {
+    #[default]
+    Myers,
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "myers" | "default" => Some(Self::Myers),
+            "minimal" => Some(Self::Minimal),
+            "patience" => Some(Self::Patience),
+            "histogram" => Some(Self::Histogram),
+            _ => None,
+        }
+    }
+
+    /// The next algorithm in the order the diff view cycles through them
+    pub fn next(self) -> Self {
+        match self {
+            Self::Myers => Self::Minimal,
+            Self::Minimal => Self::Patience,
+            Self::Patience => Self::Histogram,
+            Self::Histogram => Self::Myers,
+        }
+    }
+
+    /// Name as accepted by `git diff --diff-algorithm`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Myers => "myers",
+            Self::Minimal => "minimal",
+            Self::Patience => "patience",
+            Self::Histogram => "histogram",
+        }
+    }
+}
+
+/// Whitespace differences to ignore
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Whitespace {
+    #[default]
+    Show,
+    /// `git diff -w`
+    IgnoreAll,
+    /// `git diff --ignore-space-at-eol`
+    IgnoreAtEol,
+    /// `git diff -b`
+    IgnoreChange,
+    /// `git diff --ignore-blank-lines`
+    IgnoreBlankLines,
+}
+
+impl Whitespace {
+    /// The next mode in the order the diff view cycles through them
+    pub fn next(self) -> Self {
+        match self {
+            Whitespace::Show => Whitespace::IgnoreAll,
+            Whitespace::IgnoreAll => Whitespace::IgnoreAtEol,
+            Whitespace::IgnoreAtEol => Whitespace::IgnoreChange,
+            Whitespace::IgnoreChange => Whitespace::IgnoreBlankLines,
+            Whitespace::IgnoreBlankLines => Whitespace::Show,
+        }
+    }
+
+    pub fn label(self) -> Option<&'static str> {
+        match self {
+            Whitespace::Show => None,
+            Whitespace::IgnoreAll => Some("ignore all whitespace"),
+            Whitespace::IgnoreAtEol => Some("ignore whitespace at eol"),
+            Whitespace::IgnoreChange => Some("ignore whitespace changes"),
+            Whitespace::IgnoreBlankLines => Some("ignore blank lines"),
+        }
+    }
+}
+
+/// Context lines that show every line of a file. xdiff adds twice this and the
+/// inter-hunk context to line numbers, which must stay within a 32-bit `long`.
+const WHOLE_FILE_CONTEXT: u32 = 1 << 24;
+
+/// Adjustments to the git diff settings, made interactively in the diff view
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct DiffOverrides {
+    pub algorithm: Option<DiffAlgorithm>,
+    pub whitespace: Whitespace,
+    /// Context lines to use instead of `diff.context`
+    pub context_lines: Option<u32>,
+    /// Show every line of the file as context
+    pub whole_file: bool,
+}
+
+/// The `diff.*` and `color.diff*` settings from git config that affect the built-in diff
+#[derive(Debug, Clone)]
+pub struct GitDiffSettings {
+    pub algorithm: DiffAlgorithm,
+    pub context_lines: u32,
+    pub inter_hunk_context: u32,
+    pub whitespace: Whitespace,
+    /// Whether `color.diff` (or `color.ui`) allows colored output
+    pub color: bool,
+    /// Raw `color.diff.<slot>` values, e.g. `("new", "bold green")`
+    pub slot_colors: Vec<(String, String)>,
+}
+
+impl Default for GitDiffSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: DiffAlgorithm::default(),
+            context_lines: 3,
+            inter_hunk_context: 0,
+            whitespace: Whitespace::default(),
+            color: true,
+            slot_colors: Vec::new(),
+        }
+    }
+}
+
+impl GitDiffSettings {
+    /// These settings with the diff view's adjustments applied
+    pub fn with_overrides(mut self, overrides: &DiffOverrides) -> Self {
+        if let Some(algorithm) = overrides.algorithm {
+            self.algorithm = algorithm;
+        }
+        self.whitespace = overrides.whitespace;
+        if let Some(context_lines) = overrides.context_lines {
+            self.context_lines = context_lines;
+        }
+        if overrides.whole_file {
+            self.context_lines = WHOLE_FILE_CONTEXT;
+        }
+        self
+    }
+
+    pub(super) fn from_repo(repo: &Git2Repository) -> Self {
+        let mut settings = Self::default();
+        let Ok(config) = repo.config().and_then(|mut c| c.snapshot()) else {
+            return settings;
+        };
+
+        if let Some(algorithm) = config
+            .get_string("diff.algorithm")
+            .ok()
+            .and_then(|v| DiffAlgorithm::parse(&v))
+        {
+            settings.algorithm = algorithm;
+        }
+        if let Ok(context) = config.get_i32("diff.context") {
+            settings.context_lines = context.max(0) as u32;
+        }
+        if let Ok(context) = config.get_i32("diff.interHunkContext") {
+            settings.inter_hunk_context = context.max(0) as u32;
+        }
+
+        let color = config
+            .get_string("color.diff")
+            .or_else(|_| config.get_string("color.ui"));
+        if let Ok(color) = color {
+            settings.color = !matches!(
+                color.to_ascii_lowercase().as_str(),
+                "false" | "never" | "no" | "off" | "0"
+            );
+        }
+
+        if let Ok(mut entries) = config.entries(Some("color\\.diff\\..*")) {
+            while let Some(Ok(entry)) = entries.next() {
+                if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+                    let slot = name["color.diff.".len()..].to_ascii_lowercase();
+                    settings.slot_colors.push((slot, value.to_string()));
+                }
+            }
+        }
+
+        settings
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    Context,
+    Added,
+    Removed,
+    /// `\ No newline at end of file`
+    Marker,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffLine {
+    pub kind: LineKind,
+    /// Line number in the old version, for context and removed lines
+    pub old_lineno: Option<u32>,
+    /// Line number in the new version, for context and added lines
+    pub new_lineno: Option<u32>,
+    /// Line text without the origin character or trailing newline
+    pub content: String,
+}
+
+impl DiffLine {
+    /// The line as it appears in a patch, without the trailing newline
+    pub fn patch_line(&self) -> String {
+        let origin = match self.kind {
+            LineKind::Context => " ",
+            LineKind::Added => "+",
+            LineKind::Removed => "-",
+            LineKind::Marker => "",
+        };
+        format!("{origin}{}", self.content)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    /// The `@@ -a,b +c,d @@ context` line
+    pub header: String,
+    pub lines: Vec<DiffLine>,
+}
+
+impl Hunk {
+    /// Plain patch text of the hunk, header included
+    pub fn to_patch(&self) -> String {
+        let mut out = format!("{}\n", self.header);
+        for line in &self.lines {
+            out.push_str(&line.patch_line());
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Old and new line numbers of the lines just before the hunk (0 at the start of the file)
+    pub fn before(&self) -> (u32, u32) {
+        let ((old, old_count), (new, new_count)) =
+            parse_hunk_ranges(&self.header).unwrap_or_default();
+        // An empty side starts after the line its start names
+        let before = |start: u32, count| {
+            if count == 0 {
+                start
+            } else {
+                start.saturating_sub(1)
+            }
+        };
+        (before(old, old_count), before(new, new_count))
+    }
+
+    /// Old and new line numbers of the lines just after the hunk
+    pub fn after(&self) -> (u32, u32) {
+        let ((old, old_count), (new, new_count)) =
+            parse_hunk_ranges(&self.header).unwrap_or_default();
+        let after = |start: u32, count| if count == 0 { start + 1 } else { start + count };
+        (after(old, old_count), after(new, new_count))
+    }
+
+    /// Text after the ranges in the header, usually the enclosing function
+    fn function_context(&self) -> &str {
+        self.header
+            .get(2..)
+            .and_then(|rest| rest.find("@@"))
+            .map_or("", |pos| &self.header[pos + 4..])
+    }
+
+    /// Rewrite the ranges in the header to match the lines, keeping the function context
+    pub fn update_header(&mut self) {
+        let (old_before, new_before) = self.before();
+        let range = |linenos: Vec<u32>, before: u32| match linenos.as_slice() {
+            [] => format!("{before},0"),
+            [only] => only.to_string(),
+            [first, ..] => format!("{first},{}", linenos.len()),
+        };
+        let old = range(
+            self.lines.iter().filter_map(|l| l.old_lineno).collect(),
+            old_before,
+        );
+        let new = range(
+            self.lines.iter().filter_map(|l| l.new_lineno).collect(),
+            new_before,
+        );
+        self.header = format!("@@ -{old} +{new} @@{}", self.function_context());
+    }
+}
+
+/// Structured diff of a single file
+#[derive(Debug, Clone, Default)]
+pub struct FileDiff {
+    /// `diff --git`, `index`, `---`/`+++` and similar lines
+    pub header: Vec<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FileDiff {
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty() && self.hunks.is_empty()
+    }
+
+    /// Approximate size of the patch text in bytes
+    pub fn size(&self) -> usize {
+        let header: usize = self.header.iter().map(|line| line.len() + 1).sum();
+        let hunks: usize = self
+            .hunks
+            .iter()
+            .map(|hunk| {
+                hunk.header.len()
+                    + 1
+                    + hunk
+                        .lines
+                        .iter()
+                        .map(|line| line.content.len() + 2)
+                        .sum::<usize>()
+            })
+            .sum();
+        header + hunks
+    }
+
+    /// Drop trailing hunk lines so the patch stays within roughly `max_bytes`, keeping
+    /// the hunk that was cut a valid one. Returns whether anything was removed.
+    pub fn truncate(&mut self, max_bytes: usize) -> bool {
+        let mut size: usize = self.header.iter().map(|line| line.len() + 1).sum();
+        for (hunk_idx, hunk) in self.hunks.iter_mut().enumerate() {
+            size += hunk.header.len() + 1;
+            for (line_idx, line) in hunk.lines.iter().enumerate() {
+                size += line.content.len() + 2;
+                if size > max_bytes {
+                    hunk.lines.truncate(line_idx);
+                    // `git apply` wants a change in every hunk and context after the last
+                    // one, so end on the last unchanged line that follows a change
+                    let first_change = hunk
+                        .lines
+                        .iter()
+                        .position(|line| matches!(line.kind, LineKind::Added | LineKind::Removed));
+                    let end = first_change.and_then(|first| {
+                        hunk.lines
+                            .iter()
+                            .rposition(|line| line.kind == LineKind::Context)
+                            .filter(|&last| last > first)
+                    });
+                    match end {
+                        Some(end) => {
+                            hunk.lines.truncate(end + 1);
+                            hunk.update_header();
+                            self.hunks.truncate(hunk_idx + 1);
+                        }
+                        None => self.hunks.truncate(hunk_idx),
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Add up to `count` unchanged lines above hunk `idx`, taken from `new_lines` (the
+    /// new version of the file), merging it into the previous hunk once nothing is left
+    /// between them. Returns the index the hunk ends up at and the new line number of
+    /// the first line added, or `None` if there was nothing to add.
+    pub fn expand_above(
+        &mut self,
+        idx: usize,
+        count: u32,
+        new_lines: &[&str],
+    ) -> Option<(usize, u32)> {
+        let (old_before, new_before) = self.hunks.get(idx)?.before();
+        // Last new line that already belongs to the previous hunk
+        let limit = match idx.checked_sub(1) {
+            Some(prev) => self.hunks[prev].after().1 - 1,
+            None => 0,
+        };
+        let first = new_before.saturating_sub(count).max(limit) + 1;
+        if first > new_before {
+            return None;
+        }
+
+        let context = (first..=new_before).filter_map(|new| {
+            Some(DiffLine {
+                kind: LineKind::Context,
+                old_lineno: Some(old_before - (new_before - new)),
+                new_lineno: Some(new),
+                content: new_lines.get(new as usize - 1)?.to_string(),
+            })
+        });
+        let hunk = &mut self.hunks[idx];
+        let added = hunk.lines.len();
+        hunk.lines.splice(0..0, context);
+        let added = hunk.lines.len() - added;
+        hunk.update_header();
+        // The function context names a line above the hunk, which may now be part of it
+        let function = hunk.function_context().trim();
+        if !function.is_empty()
+            && hunk.lines[..added]
+                .iter()
+                .any(|line| line.content.trim() == function)
+        {
+            let len = hunk.header.len() - hunk.function_context().len();
+            hunk.header.truncate(len);
+        }
+
+        if idx > 0 && first == limit + 1 {
+            let hunk = self.hunks.remove(idx);
+            let prev = &mut self.hunks[idx - 1];
+            prev.lines.extend(hunk.lines);
+            prev.update_header();
+            return Some((idx - 1, first));
+        }
+        Some((idx, first))
+    }
+
+    /// Add up to `count` unchanged lines below hunk `idx`, like `expand_above`
+    pub fn expand_below(
+        &mut self,
+        idx: usize,
+        count: u32,
+        new_lines: &[&str],
+    ) -> Option<(usize, u32)> {
+        let hunk = self.hunks.get(idx)?;
+        // A "no newline" marker means the hunk already reaches the end of the file
+        if hunk
+            .lines
+            .last()
+            .map_or(true, |line| line.kind == LineKind::Marker)
+        {
+            return None;
+        }
+        let (old_after, new_after) = hunk.after();
+        // Last new line before the next hunk, or the end of the file
+        let limit = match self.hunks.get(idx + 1) {
+            Some(next) => next.before().1,
+            None => new_lines.len() as u32,
+        };
+        let last = (new_after + count).saturating_sub(1).min(limit);
+        if count == 0 || last < new_after {
+            return None;
+        }
+
+        let context = (new_after..=last).filter_map(|new| {
+            Some(DiffLine {
+                kind: LineKind::Context,
+                old_lineno: Some(old_after + (new - new_after)),
+                new_lineno: Some(new),
+                content: new_lines.get(new as usize - 1)?.to_string(),
+            })
+        });
+        let hunk = &mut self.hunks[idx];
+        hunk.lines.extend(context);
+        hunk.update_header();
+
+        if idx + 1 < self.hunks.len() && last == limit {
+            let next = self.hunks.remove(idx + 1);
+            let hunk = &mut self.hunks[idx];
+            hunk.lines.extend(next.lines);
+            hunk.update_header();
+        }
+        Some((idx, new_after))
+    }
+
+    /// Plain unified diff text, as `git diff --no-color` would print it
+    pub fn to_patch(&self) -> Vec<u8> {
+        let mut out = String::new();
+        for line in &self.header {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for hunk in &self.hunks {
+            out.push_str(&hunk.to_patch());
+        }
+        out.into_bytes()
+    }
+}
+
+/// Diff `HEAD` against the working tree (including staged changes) for a single path
+pub(super) fn file_diff(
+    repo: &Git2Repository,
+    path: &str,
+    settings: &GitDiffSettings,
+) -> Result<FileDiff> {
+    let mut opts = diff_options(settings);
+    opts.pathspec(path).disable_pathspec_match(true);
+    let diffs =
+        workdir_diff(repo, &mut opts, None).with_context(|| format!("Failed to diff '{path}'"))?;
+    Ok(diffs
+        .into_iter()
+        .next()
+        .map(|(_, file_diff)| file_diff)
+        .unwrap_or_default())
+}
+
+/// Patch of `HEAD` against the working tree for `paths` (every changed file when empty)
+/// in a form `git apply` accepts, as pairs of path and patch. Binary changes are included
+/// in full, submodules left out, and the bytes of the files are kept as they are.
+pub(super) fn export_diff(
+    repo: &Git2Repository,
+    paths: &[String],
+    settings: &GitDiffSettings,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut opts = diff_options(settings);
+    opts.ignore_submodules(true)
+        .show_binary(true)
+        .disable_pathspec_match(true);
+    for path in paths {
+        opts.pathspec(path);
+    }
+    let head_tree = match repo.head() {
+        Ok(head) => Some(head.peel_to_tree()?),
+        // Unborn branch: everything is new
+        Err(_) => None,
+    };
+    let diff = repo
+        .diff_tree_to_workdir_with_index(head_tree.as_ref(), Some(&mut opts))
+        .context("Failed to diff the working tree")?;
+
+    let mut patches: Vec<(String, Vec<u8>)> = Vec::new();
+    diff.print(DiffFormat::Patch, |delta, _hunk, line| {
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if patches.last().map_or(true, |(last, _)| *last != path) {
+            patches.push((path, Vec::new()));
+        }
+        let Some((_, patch)) = patches.last_mut() else {
+            return true;
+        };
+        if let origin @ ('+' | '-' | ' ') = line.origin() {
+            patch.push(origin as u8);
+        }
+        patch.extend_from_slice(line.content());
+        true
+    })
+    .context("Failed to diff the working tree")?;
+    Ok(patches)
+}
+
+/// Keep the hunks of a single file's patch whose `@@` header `keep` accepts, along
+/// with the file header. `None` when no hunk is kept.
+pub fn retain_hunks(patch: &[u8], keep: impl Fn(&str) -> bool) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(patch.len());
+    let (mut keeping, mut kept_any) = (true, false);
+    for line in patch.split_inclusive(|&byte| byte == b'\n') {
+        // Lines inside hunks start with ' ', '+', '-' or '\\', never "@@"
+        if line.starts_with(b"@@") {
+            keeping = keep(&String::from_utf8_lossy(line));
+            kept_any |= keeping;
+        }
+        if keeping {
+            out.extend_from_slice(line);
+        }
+    }
+    kept_any.then_some(out)
+}
+
+/// Diff `HEAD` against the working tree for every changed file except submodules,
+/// as pairs of path and diff. Files get the same diff `get_diff` shows for them: the
+/// histogram algorithm comes from git for the files git reports. Setting `cancel`
+/// stops the work early with an error.
+pub(super) fn changeset_diff(
+    repo: &Git2Repository,
+    settings: &GitDiffSettings,
+    cancel: &AtomicBool,
+) -> Result<Vec<(String, FileDiff)>> {
+    let mut opts = diff_options(settings);
+    opts.ignore_submodules(true);
+    let mut diffs =
+        workdir_diff(repo, &mut opts, Some(cancel)).context("Failed to diff the working tree")?;
+
+    if settings.algorithm == DiffAlgorithm::Histogram {
+        // Without git, `get_diff` falls back to libgit2's patience diff as well
+        if let Some(patches) = repo
+            .workdir()
+            .and_then(|workdir| run_git_diff(workdir, settings, &[], Some(cancel)).ok())
+        {
+            let mut git_diffs = parse_patches(&String::from_utf8_lossy(&patches));
+            for (path, file_diff) in &mut diffs {
+                if let Some(idx) = git_diffs.iter().position(|(git_path, _)| git_path == path) {
+                    *file_diff = git_diffs.swap_remove(idx).1;
+                }
+            }
+        }
+    }
+    Ok(diffs)
+}
+
+fn diff_options(settings: &GitDiffSettings) -> DiffOptions {
+    let mut opts = DiffOptions::new();
+    opts.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true)
+        .context_lines(settings.context_lines)
+        .interhunk_lines(settings.inter_hunk_context);
+    match settings.whitespace {
+        Whitespace::Show => {}
+        Whitespace::IgnoreAll => {
+            opts.ignore_whitespace(true);
+        }
+        Whitespace::IgnoreAtEol => {
+            opts.ignore_whitespace_eol(true);
+        }
+        Whitespace::IgnoreChange => {
+            opts.ignore_whitespace_change(true);
+        }
+        Whitespace::IgnoreBlankLines => {
+            opts.ignore_blank_lines(true);
+        }
+    }
+    match settings.algorithm {
+        DiffAlgorithm::Myers => {}
+        DiffAlgorithm::Minimal => {
+            opts.minimal(true);
+        }
+        // libgit2 has no histogram implementation; `git_file_diff` runs git for it,
+        // and patience is the closest match when that isn't possible
+        DiffAlgorithm::Patience | DiffAlgorithm::Histogram => {
+            opts.patience(true);
+        }
+    }
+    opts
+}
+
+fn workdir_diff(
+    repo: &Git2Repository,
+    opts: &mut DiffOptions,
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<(String, FileDiff)>> {
+    let head_tree = match repo.head() {
+        Ok(head) => Some(head.peel_to_tree()?),
+        // Unborn branch: everything is new
+        Err(_) => None,
+    };
+    let diff = repo.diff_tree_to_workdir_with_index(head_tree.as_ref(), Some(opts))?;
+
+    let mut diffs: Vec<(String, FileDiff)> = Vec::new();
+    diff.print(DiffFormat::Patch, |delta, _hunk, line| {
+        // Returning false makes libgit2 stop with an error
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return false;
+        }
+        let path = delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if diffs.last().map_or(true, |(last, _)| *last != path) {
+            diffs.push((path, FileDiff::default()));
+        }
+        let Some((_, file_diff)) = diffs.last_mut() else {
+            return true;
+        };
+
+        let content = String::from_utf8_lossy(line.content());
+        match line.origin() {
+            'F' | 'B' => {
+                file_diff.header.extend(content.lines().map(str::to_string));
+            }
+            'H' => {
+                file_diff.hunks.push(Hunk {
+                    header: content.trim_end_matches(['\r', '\n']).to_string(),
+                    lines: Vec::new(),
+                });
+            }
+            origin => {
+                let Some(current) = file_diff.hunks.last_mut() else {
+                    return true;
+                };
+                let (kind, content) = match origin {
+                    '+' => (LineKind::Added, content.trim_end_matches('\n')),
+                    '-' => (LineKind::Removed, content.trim_end_matches('\n')),
+                    ' ' => (LineKind::Context, content.trim_end_matches('\n')),
+                    // '=', '>' and '<' carry "\n\ No newline at end of file\n"
+                    _ => (LineKind::Marker, content.trim_matches('\n')),
+                };
+                current.lines.push(DiffLine {
+                    kind,
+                    old_lineno: line.old_lineno(),
+                    new_lineno: line.new_lineno(),
+                    content: content.to_string(),
+                });
+            }
+        }
+        true
+    })?;
+
+    Ok(diffs)
+}
+
+/// Diff `HEAD` against the working tree for a single path by running `git diff`, for
+/// settings libgit2 can't honor (the histogram algorithm). Returns `None` when git has
+/// nothing to say about the path, e.g. because it is untracked.
+pub(super) fn git_file_diff(
+    workdir: &Path,
+    path: &str,
+    settings: &GitDiffSettings,
+) -> Result<Option<FileDiff>> {
+    let patch = git_file_patch(workdir, path, settings)?;
+    Ok(patch.map(|patch| parse_patch(&String::from_utf8_lossy(&patch))))
+}
+
+/// Output of `git diff` for a single path, byte for byte; `None` when git has nothing
+/// to say about it
+pub(super) fn git_file_patch(
+    workdir: &Path,
+    path: &str,
+    settings: &GitDiffSettings,
+) -> Result<Option<Vec<u8>>> {
+    let patch = run_git_diff(workdir, settings, &[path], None)?;
+    Ok((!patch.is_empty()).then_some(patch))
+}
+
+/// Output of `git diff` between `HEAD` and the working tree for `paths` (every
+/// tracked file when empty). Setting `cancel` kills git.
+fn run_git_diff(
+    workdir: &Path,
+    settings: &GitDiffSettings,
+    paths: &[&str],
+    cancel: Option<&AtomicBool>,
+) -> Result<Vec<u8>> {
+    let mut cmd = Command::new("git");
+    // Patches name files as a/<path> and b/<path>, whatever the user's prefix settings
+    cmd.current_dir(workdir)
+        .args([
+            "-c",
+            "diff.noprefix=false",
+            "-c",
+            "diff.mnemonicPrefix=false",
+        ])
+        .args(["diff", "--no-color", "--no-ext-diff", "--no-textconv"])
+        .args(["--src-prefix=a/", "--dst-prefix=b/"])
+        .args(["--no-renames", "--ignore-submodules"])
+        .arg(format!("--diff-algorithm={}", settings.algorithm.name()))
+        .arg(format!("--unified={}", settings.context_lines))
+        .arg(format!(
+            "--inter-hunk-context={}",
+            settings.inter_hunk_context
+        ));
+    match settings.whitespace {
+        Whitespace::Show => {}
+        Whitespace::IgnoreAll => {
+            cmd.arg("--ignore-all-space");
+        }
+        Whitespace::IgnoreAtEol => {
+            cmd.arg("--ignore-space-at-eol");
+        }
+        Whitespace::IgnoreChange => {
+            cmd.arg("--ignore-space-change");
+        }
+        Whitespace::IgnoreBlankLines => {
+            cmd.arg("--ignore-blank-lines");
+        }
+    }
+    cmd.args(["HEAD", "--"]).args(paths);
+
+    let output = match cancel {
+        Some(cancel) => output_unless_cancelled(cmd, cancel)?,
+        None => cmd.output().context("Failed to run git")?,
+    };
+    if !output.status.success() {
+        bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Run `cmd` to completion like `Command::output`, killing it once `cancel` is set
+fn output_unless_cancelled(mut cmd: Command, cancel: &AtomicBool) -> Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run git")?;
+    // Read both pipes while waiting, so a full pipe can't stall the process
+    fn read_all(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buffer);
+            }
+            buffer
+        })
+    }
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            });
+        }
+        if cancel.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Cancelled");
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Split the output of `git diff --no-renames` for several files into pairs of path
+/// and diff. Files whose names git had to quote are left out.
+fn parse_patches(patches: &str) -> Vec<(String, FileDiff)> {
+    let mut starts: Vec<usize> = patches
+        .match_indices("diff --git ")
+        .map(|(pos, _)| pos)
+        .filter(|&pos| pos == 0 || patches.as_bytes()[pos - 1] == b'\n')
+        .collect();
+    starts.push(patches.len());
+
+    starts
+        .windows(2)
+        .filter_map(|bounds| {
+            let patch = &patches[bounds[0]..bounds[1]];
+            // "diff --git a/<path> b/<path>", with the same path on both sides
+            let names = patch.lines().next()?.strip_prefix("diff --git ")?;
+            let len = names.len().checked_sub(5)? / 2;
+            let path = names.get(2..2 + len)?;
+            (names.starts_with("a/") && names.get(2 + len..)? == format!(" b/{path}"))
+                .then(|| (path.to_string(), parse_patch(patch)))
+        })
+        .collect()
+}
+
+/// Parse the unified diff of a single file, as printed by `git diff`. Carriage returns
+/// at the end of lines belong to their content, as in `workdir_diff`.
+fn parse_patch(patch: &str) -> FileDiff {
+    let mut file_diff = FileDiff::default();
+    let (mut old_lineno, mut new_lineno) = (0, 0);
+
+    for line in patch.strip_suffix('\n').unwrap_or(patch).split('\n') {
+        if line.starts_with("@@") {
+            let line = line.trim_end_matches('\r');
+            ((old_lineno, _), (new_lineno, _)) = parse_hunk_ranges(line).unwrap_or_default();
+            file_diff.hunks.push(Hunk {
+                header: line.to_string(),
+                lines: Vec::new(),
+            });
+            continue;
+        }
+        let Some(hunk) = file_diff.hunks.last_mut() else {
+            file_diff
+                .header
+                .push(line.trim_end_matches('\r').to_string());
+            continue;
+        };
+
+        let (kind, old, new, content) = match line.as_bytes().first() {
+            Some(b'+') => (LineKind::Added, None, Some(new_lineno), &line[1..]),
+            Some(b'-') => (LineKind::Removed, Some(old_lineno), None, &line[1..]),
+            Some(b'\\') => (LineKind::Marker, None, None, line),
+            // An empty context line may have lost its leading space
+            _ => (
+                LineKind::Context,
+                Some(old_lineno),
+                Some(new_lineno),
+                line.get(1..).unwrap_or(""),
+            ),
+        };
+        if old.is_some() {
+            old_lineno += 1;
+        }
+        if new.is_some() {
+            new_lineno += 1;
+        }
+        hunk.lines.push(DiffLine {
+            kind,
+            old_lineno: old,
+            new_lineno: new,
+            content: content.to_string(),
+        });
+    }
+
+    file_diff
+}
+
+/// The `(start, count)` of both sides in a `@@ -a,b +c,d @@` header
+pub fn parse_hunk_ranges(header: &str) -> Option<((u32, u32), (u32, u32))> {
+    let mut ranges = header.split_whitespace().skip(1);
+    let range = |range: &str| {
+        let mut parts = range.get(1..)?.split(',');
+        let start = parts.next()?.parse::<u32>().ok()?;
+        // A count of one is left out
+        let count = match parts.next() {
+            Some(count) => count.parse::<u32>().ok()?,
+            None => 1,
+        };
+        Some((start, count))
+    };
+    let old = range(ranges.next()?)?;
+    let new = range(ranges.next()?)?;
+    Some((old, new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hunk_ranges_default_count_to_one() {
+        assert_eq!(
+            parse_hunk_ranges("@@ -3,7 +3,8 @@ fn main() {"),
+            Some(((3, 7), (3, 8)))
+        );
+        assert_eq!(parse_hunk_ranges("@@ -5 +0,0 @@"), Some(((5, 1), (0, 0))));
+        assert_eq!(parse_hunk_ranges("@@ -a,1 +1 @@"), None);
+        assert_eq!(parse_hunk_ranges("not a header"), None);
+    }
+
+    #[test]
+    fn parse_patch_numbers_lines() {
+        let patch = "diff --git a/f b/f\n\
+                     --- a/f\n\
+                     +++ b/f\n\
+                     @@ -1,3 +1,3 @@ fn f() {\n \
+                     a\n\
+                     -b\n\
+                     +c\n\
+                     \n\
+                     \\ No newline at end of file\n";
+        let file_diff = parse_patch(patch);
+        assert_eq!(file_diff.header.len(), 3);
+        let [hunk] = file_diff.hunks.as_slice() else {
+            panic!("expected one hunk");
+        };
+        assert_eq!(hunk.header, "@@ -1,3 +1,3 @@ fn f() {");
+        let lines: Vec<_> = hunk
+            .lines
+            .iter()
+            .map(|line| {
+                (
+                    line.kind,
+                    line.old_lineno,
+                    line.new_lineno,
+                    line.content.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (LineKind::Context, Some(1), Some(1), "a"),
+                (LineKind::Removed, Some(2), None, "b"),
+                (LineKind::Added, None, Some(2), "c"),
+                // An empty context line that lost its leading space
+                (LineKind::Context, Some(3), Some(3), ""),
+                (LineKind::Marker, None, None, "\\ No newline at end of file"),
+            ]
+        );
+    }
+
+    /// Lines "l1" to "l20", the new version of the file in the expand tests
+    fn new_lines() -> Vec<String> {
+        (1..=20).map(|n| format!("l{n}")).collect()
+    }
+
+    fn headers(file_diff: &FileDiff) -> Vec<&str> {
+        file_diff
+            .hunks
+            .iter()
+            .map(|hunk| hunk.header.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn expand_adds_context_and_fixes_headers() {
+        let lines = new_lines();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut file_diff = parse_patch("@@ -8,3 +8,3 @@ fn f() {\n l8\n-old\n+l9\n l10\n");
+
+        assert_eq!(file_diff.expand_above(0, 3, &lines), Some((0, 5)));
+        assert_eq!(headers(&file_diff), ["@@ -5,6 +5,6 @@ fn f() {"]);
+        let first = &file_diff.hunks[0].lines[0];
+        assert_eq!(
+            (first.old_lineno, first.new_lineno, first.content.as_str()),
+            (Some(5), Some(5), "l5")
+        );
+
+        assert_eq!(file_diff.expand_below(0, 2, &lines), Some((0, 11)));
+        assert_eq!(headers(&file_diff), ["@@ -5,8 +5,8 @@ fn f() {"]);
+        assert_eq!(file_diff.hunks[0].lines.last().unwrap().content, "l12");
+
+        // Up to the start of the file, keeping the function context
+        assert_eq!(file_diff.expand_above(0, 10, &lines), Some((0, 1)));
+        assert_eq!(file_diff.expand_above(0, 10, &lines), None);
+        assert_eq!(headers(&file_diff), ["@@ -1,12 +1,12 @@ fn f() {"]);
+        // Down to the end of the file
+        assert_eq!(file_diff.expand_below(0, 100, &lines), Some((0, 13)));
+        assert_eq!(file_diff.expand_below(0, 1, &lines), None);
+        assert_eq!(headers(&file_diff), ["@@ -1,20 +1,20 @@ fn f() {"]);
+    }
+
+    #[test]
+    fn expand_merges_hunks_that_meet() {
+        let lines = new_lines();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let patch = "@@ -2,3 +2,3 @@\n l2\n-x\n+l3\n l4\n\
+                     @@ -8,3 +8,3 @@\n l8\n-y\n+l9\n l10\n";
+
+        let mut file_diff = parse_patch(patch);
+        assert_eq!(file_diff.expand_above(1, 10, &lines), Some((0, 5)));
+        assert_eq!(headers(&file_diff), ["@@ -2,9 +2,9 @@"]);
+
+        let mut file_diff = parse_patch(patch);
+        assert_eq!(file_diff.expand_below(0, 2, &lines), Some((0, 5)));
+        assert_eq!(file_diff.hunks.len(), 2);
+        assert_eq!(file_diff.expand_below(0, 2, &lines), Some((0, 7)));
+        assert_eq!(headers(&file_diff), ["@@ -2,9 +2,9 @@"]);
+        let linenos: Vec<_> = file_diff.hunks[0]
+            .lines
+            .iter()
+            .filter_map(|line| line.new_lineno)
+            .collect();
+        assert_eq!(linenos, (2..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retain_hunks_keeps_the_file_header() {
+        let patch: &[u8] = b"diff --git a/f b/f\n--- a/f\n+++ b/f\n\
+                             @@ -1 +1 @@\n-a\n+\xe9\n\
+                             @@ -5 +5 @@\n-b\n+c\n";
+        let second = |header: &str| header.starts_with("@@ -5");
+        assert_eq!(
+            retain_hunks(patch, second).unwrap(),
+            b"diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -5 +5 @@\n-b\n+c\n"
+        );
+        let first = retain_hunks(patch, |header| !second(header)).unwrap();
+        assert!(first.ends_with(b"@@ -1 +1 @@\n-a\n+\xe9\n"));
+        assert_eq!(retain_hunks(patch, |_| false), None);
+    }
+
+    #[test]
+    fn git_diff_ignores_prefix_settings() {
+        let dir = crate::git::repo_with_files(&[("f.txt", "a\n")]);
+        let mut config = Git2Repository::open(dir.path()).unwrap().config().unwrap();
+        config.set_bool("diff.noprefix", true).unwrap();
+        config.set_bool("diff.mnemonicPrefix", true).unwrap();
+        std::fs::write(dir.path().join("f.txt"), "b\n").unwrap();
+
+        let settings = GitDiffSettings::default();
+        let patch = git_file_patch(dir.path(), "f.txt", &settings)
+            .unwrap()
+            .unwrap();
+        assert!(patch.starts_with(b"diff --git a/f.txt b/f.txt\n"));
+        assert!(String::from_utf8_lossy(&patch).contains("\n--- a/f.txt\n+++ b/f.txt\n"));
+        let patches = run_git_diff(dir.path(), &settings, &[], None).unwrap();
+        let files = parse_patches(&String::from_utf8_lossy(&patches));
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "f.txt");
+    }
+
+    #[test]
+    fn parse_patches_splits_files() {
+        let patches = "diff --git a/a b/a\n\
+                       --- a/a\n\
+                       +++ b/a\n\
+                       @@ -1 +1 @@\n\
+                       -x\n\
+                       +diff --git a/y b/y\n\
+                       diff --git a/dir/b c b/dir/b c\n\
+                       Binary files a/dir/b c and b/dir/b c differ\n\
+                       diff --git \"a/q\\tr\" \"b/q\\tr\"\n";
+        let diffs = parse_patches(patches);
+        let paths: Vec<_> = diffs.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["a", "dir/b c"]);
+        assert_eq!(diffs[0].1.hunks[0].lines.len(), 2);
+        assert!(diffs[1].1.hunks.is_empty());
+    }
+
+    #[test]
+    fn parse_patch_keeps_carriage_returns() {
+        let patch = "--- a/f\r\n+++ b/f\r\n@@ -1,2 +1,2 @@\r\n a\r\n-b\r\n+c\r\n";
+        let file_diff = parse_patch(patch);
+        assert_eq!(file_diff.header, ["--- a/f", "+++ b/f"]);
+        assert_eq!(file_diff.hunks[0].header, "@@ -1,2 +1,2 @@");
+        let contents: Vec<_> = file_diff.hunks[0]
+            .lines
+            .iter()
+            .map(|line| line.content.as_str())
+            .collect();
+        assert_eq!(contents, ["a\r", "b\r", "c\r"]);
+        assert_eq!(
+            String::from_utf8(file_diff.to_patch()).unwrap(),
+            "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a\r\n-b\r\n+c\r\n"
+        );
+    }
+}
@@ -1,5 +1,14 @@
-use anyhow::{Context, Result};
-use git2::{Repository as Git2Repository, StatusOptions};
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use git2::{Oid, Repository as Git2Repository, StatusOptions, SubmoduleIgnore, SubmoduleStatus};
+
+use super::patch::{self, FileDiff, GitDiffSettings};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileStatus {
@@ -8,6 +17,7 @@ pub enum FileStatus {
     Deleted,
     Renamed,
     Untracked,
+    Submodule,
 }
 
 #[derive(Debug, Clone)]
@@ -16,18 +26,213 @@ pub struct ChangedFile {
     pub status: FileStatus,
 }
 
+/// Identifies the content on both sides of a file's diff, so results can be
+/// reused until either side changes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileVersion {
+    /// Blob in `HEAD`, if the file exists there
+    pub old: Option<Oid>,
+    pub new: NewVersion,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NewVersion {
+    /// File in the working tree, identified by its modification time and size
+    Worktree {
+        modified: SystemTime,
+        size: u64,
+    },
+    /// Staged blob, when the file is missing from the working tree
+    Index(Oid),
+    Missing,
+}
+
+/// A file as stored in `HEAD`
+pub struct HeadBlob {
+    pub id: Oid,
+    /// Git file mode, e.g. `0o100644`
+    pub mode: i32,
+    pub content: Vec<u8>,
+}
+
 pub struct Repository {
     inner: Git2Repository,
 }
 
 impl Repository {
-    pub fn open_current_dir() -> Result<Self> {
-        let repo = Git2Repository::discover(".").context(
-            "Failed to find git repository. Please run this command inside a git repository.",
-        )?;
+    /// Find the repository containing `dir`, searching parent directories
+    pub fn discover(dir: &Path) -> Result<Self> {
+        let repo = Git2Repository::discover(dir).with_context(|| {
+            format!(
+                "Failed to find git repository in '{}'. Please run this command inside a git repository.",
+                dir.display()
+            )
+        })?;
         Ok(Self { inner: repo })
     }
 
+    /// Root of the working tree that status paths are relative to
+    pub fn workdir(&self) -> &Path {
+        self.inner.workdir().unwrap_or_else(|| self.inner.path())
+    }
+
+    /// `path`, given relative to `base`, as a path relative to the root of the working tree
+    pub fn relative_path(&self, base: &Path, path: &Path) -> Result<String> {
+        let workdir = fs::canonicalize(self.workdir())
+            .with_context(|| format!("Failed to resolve '{}'", self.workdir().display()))?;
+        let full = base.join(path);
+        // Deleted files no longer exist, so resolve their closest existing parent
+        let mut existing = full.as_path();
+        let mut missing: Vec<&std::ffi::OsStr> = Vec::new();
+        let resolved = loop {
+            if let Ok(resolved) = fs::canonicalize(existing) {
+                break missing
+                    .iter()
+                    .rev()
+                    .fold(resolved, |resolved: PathBuf, part| resolved.join(part));
+            }
+            match (existing.file_name(), existing.parent()) {
+                (Some(name), Some(parent)) => {
+                    missing.push(name);
+                    existing = parent;
+                }
+                _ => bail!("Failed to resolve '{}'", path.display()),
+            }
+        };
+        let relative = resolved
+            .strip_prefix(&workdir)
+            .ok()
+            .with_context(|| format!("'{}' is outside the repository", path.display()))?;
+        Ok(relative.to_string_lossy().into_owned())
+    }
+
+    /// Read the git config settings that shape the built-in diff
+    pub fn diff_settings(&self) -> GitDiffSettings {
+        GitDiffSettings::from_repo(&self.inner)
+    }
+
+    /// Compute the diff of a single path in-process
+    pub fn file_diff(&self, path: &str, settings: &GitDiffSettings) -> Result<FileDiff> {
+        patch::file_diff(&self.inner, path, settings)
+    }
+
+    /// Compute the diffs of all changed files except submodules, giving up with an
+    /// error once `cancel` is set
+    pub fn changeset_diff(
+        &self,
+        settings: &GitDiffSettings,
+        cancel: &AtomicBool,
+    ) -> Result<Vec<(String, FileDiff)>> {
+        patch::changeset_diff(&self.inner, settings, cancel)
+    }
+
+    /// Compute the patches of `paths` (all changed files when empty) that `git apply`
+    /// accepts, by file
+    pub fn export_diff(
+        &self,
+        paths: &[String],
+        settings: &GitDiffSettings,
+    ) -> Result<Vec<(String, Vec<u8>)>> {
+        patch::export_diff(&self.inner, paths, settings)
+    }
+
+    /// Compute the diff of a single path with the `git` command, for settings that
+    /// libgit2 doesn't support. `None` when git doesn't report the path.
+    pub fn git_file_diff(
+        &self,
+        path: &str,
+        settings: &GitDiffSettings,
+    ) -> Result<Option<FileDiff>> {
+        patch::git_file_diff(self.workdir(), path, settings)
+    }
+
+    /// Patch of a single path from the `git` command, byte for byte. `None` when git
+    /// doesn't report the path.
+    pub fn git_file_patch(
+        &self,
+        path: &str,
+        settings: &GitDiffSettings,
+    ) -> Result<Option<Vec<u8>>> {
+        patch::git_file_patch(self.workdir(), path, settings)
+    }
+
+    /// Current version of both sides of the diff for `path`
+    pub fn file_version(&self, path: &str) -> FileVersion {
+        let old = self
+            .inner
+            .head()
+            .and_then(|head| head.peel_to_tree())
+            .and_then(|tree| tree.get_path(Path::new(path)))
+            .map(|entry| entry.id())
+            .ok();
+
+        let new = match fs::symlink_metadata(self.workdir().join(path)) {
+            Ok(metadata) => NewVersion::Worktree {
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                size: metadata.len(),
+            },
+            Err(_) => self
+                .inner
+                .index()
+                .ok()
+                .and_then(|index| index.get_path(Path::new(path), 0))
+                .map_or(NewVersion::Missing, |entry| NewVersion::Index(entry.id)),
+        };
+
+        FileVersion { old, new }
+    }
+
+    /// Contents of `path` in `HEAD`, if it exists there
+    pub fn head_blob(&self, path: &str) -> Option<HeadBlob> {
+        let tree = self.inner.head().ok()?.peel_to_tree().ok()?;
+        let entry = tree.get_path(Path::new(path)).ok()?;
+        let blob = self.inner.find_blob(entry.id()).ok()?;
+        Some(HeadBlob {
+            id: entry.id(),
+            mode: entry.filemode(),
+            content: blob.content().to_vec(),
+        })
+    }
+
+    /// Contents of the new side of `version` of `path`: the working tree file, or the
+    /// staged blob when it is missing from the working tree
+    pub fn new_content(&self, path: &str, version: &FileVersion) -> Result<Vec<u8>> {
+        match version.new {
+            NewVersion::Worktree { .. } => self.worktree_file(path),
+            NewVersion::Index(oid) => Ok(self
+                .inner
+                .find_blob(oid)
+                .with_context(|| format!("Failed to read '{path}' from the index"))?
+                .content()
+                .to_vec()),
+            NewVersion::Missing => Ok(Vec::new()),
+        }
+    }
+
+    /// Contents of `path` in the working tree
+    pub fn worktree_file(&self, path: &str) -> Result<Vec<u8>> {
+        fs::read(self.workdir().join(path)).with_context(|| format!("Failed to read '{path}'"))
+    }
+
+    /// Size in bytes of the larger side of a diff, to spot files too big to diff speculatively
+    pub fn version_size(&self, version: &FileVersion) -> u64 {
+        let old = version
+            .old
+            .and_then(|oid| self.inner.odb().ok()?.read_header(oid).ok())
+            .map_or(0, |(size, _)| size as u64);
+        let new = match version.new {
+            NewVersion::Worktree { size, .. } => size,
+            NewVersion::Index(oid) => self
+                .inner
+                .odb()
+                .ok()
+                .and_then(|odb| odb.read_header(oid).ok())
+                .map_or(0, |(size, _)| size as u64),
+            NewVersion::Missing => 0,
+        };
+        old.max(new)
+    }
+
     pub fn get_changed_files(&self) -> Result<Vec<ChangedFile>> {
         let mut opts = StatusOptions::new();
         opts.include_untracked(true)
@@ -39,13 +244,16 @@ impl Repository {
             .statuses(Some(&mut opts))
             .context("Failed to get repository status")?;
 
+        let submodules = self.submodule_paths();
         let mut files = Vec::new();
 
         for entry in statuses.iter() {
             let path = entry.path().unwrap_or("").to_string();
             let status = entry.status();
 
-            let file_status = if status.is_index_new() || status.is_wt_new() {
+            let file_status = if submodules.contains(path.trim_end_matches('/')) {
+                FileStatus::Submodule
+            } else if status.is_index_new() || status.is_wt_new() {
                 FileStatus::Added
             } else if status.is_index_deleted() || status.is_wt_deleted() {
                 FileStatus::Deleted
@@ -60,7 +268,7 @@ impl Repository {
             };
 
             files.push(ChangedFile {
-                path,
+                path: path.trim_end_matches('/').to_string(),
                 status: file_status,
             });
         }
@@ -68,4 +276,157 @@ impl Repository {
         files.sort_by(|a, b| a.path.cmp(&b.path));
         Ok(files)
     }
+
+    fn submodule_paths(&self) -> HashSet<String> {
+        self.inner
+            .submodules()
+            .map(|subs| {
+                subs.iter()
+                    .filter_map(|sub| sub.path().to_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Open the repository checked out at the given submodule path
+    pub fn open_submodule(&self, path: &str) -> Result<Self> {
+        let submodule = self
+            .inner
+            .find_submodule(path)
+            .with_context(|| format!("Failed to find submodule '{path}'"))?;
+        let repo = submodule
+            .open()
+            .with_context(|| format!("Submodule '{path}' is not checked out"))?;
+        Ok(Self { inner: repo })
+    }
+
+    /// Summarize a submodule pointer change as a short log of the commits between the
+    /// recorded and the checked out commit, similar to `git diff --submodule=log`.
+    pub fn submodule_summary(&self, path: &str) -> Result<Vec<u8>> {
+        let submodule = self
+            .inner
+            .find_submodule(path)
+            .with_context(|| format!("Failed to find submodule '{path}'"))?;
+        let old = submodule.head_id();
+        let new = submodule.workdir_id().or_else(|| submodule.index_id());
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "\x1b[1mSubmodule {} {}..{}:\x1b[0m",
+            path,
+            short_id(old),
+            short_id(new)
+        );
+
+        match (old, new) {
+            (Some(old), Some(new)) if old != new => match submodule.open() {
+                Ok(sub_repo) => {
+                    let added = commits_between(&sub_repo, new, old);
+                    let removed = commits_between(&sub_repo, old, new);
+                    match (added, removed) {
+                        (Ok(added), Ok(removed)) => {
+                            for line in added {
+                                let _ = writeln!(out, "  \x1b[32m>\x1b[0m {line}");
+                            }
+                            for line in removed {
+                                let _ = writeln!(out, "  \x1b[31m<\x1b[0m {line}");
+                            }
+                        }
+                        _ => {
+                            let _ = writeln!(out, "  (commits not present)");
+                        }
+                    }
+                }
+                Err(_) => {
+                    let _ = writeln!(out, "  (submodule not checked out)");
+                }
+            },
+            (None, Some(_)) => {
+                let _ = writeln!(out, "  (new submodule)");
+            }
+            (Some(_), None) => {
+                let _ = writeln!(out, "  (submodule deleted)");
+            }
+            _ => {}
+        }
+
+        let status = self
+            .inner
+            .submodule_status(path, SubmoduleIgnore::None)
+            .unwrap_or(SubmoduleStatus::empty());
+        if status.intersects(SubmoduleStatus::WD_INDEX_MODIFIED | SubmoduleStatus::WD_WD_MODIFIED) {
+            let _ = writeln!(out, "Submodule {path} contains modified content");
+        }
+        if status.contains(SubmoduleStatus::WD_UNTRACKED) {
+            let _ = writeln!(out, "Submodule {path} contains untracked content");
+        }
+
+        Ok(out.into_bytes())
+    }
+}
+
+fn short_id(id: Option<Oid>) -> String {
+    id.map(|id| id.to_string()[..7].to_string())
+        .unwrap_or_else(|| "0000000".to_string())
+}
+
+/// One-line descriptions of the commits reachable from `from` but not from `hidden`
+fn commits_between(repo: &Git2Repository, from: Oid, hidden: Oid) -> Result<Vec<String>> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(from)?;
+    revwalk.hide(hidden)?;
+
+    let mut lines = Vec::new();
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        lines.push(format!(
+            "\x1b[33m{}\x1b[0m {}",
+            short_id(Some(commit.id())),
+            commit.summary().unwrap_or("")
+        ));
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Temporary repository with `files` committed to `HEAD`
+    pub fn repo_with_files(files: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Git2Repository::init(dir.path()).unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            let full = dir.path().join(path);
+            fs::create_dir_all(full.parent().unwrap()).unwrap();
+            fs::write(&full, content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        dir
+    }
+
+    #[test]
+    fn relative_path_resolves_against_the_base() {
+        let dir = repo_with_files(&[("a.txt", "a\n"), ("sub/b.txt", "b\n")]);
+        let repository = Repository::discover(dir.path()).unwrap();
+        let sub = dir.path().join("sub");
+        let relative = |base: &Path, path: &str| repository.relative_path(base, Path::new(path));
+
+        assert_eq!(relative(dir.path(), "sub/b.txt").unwrap(), "sub/b.txt");
+        assert_eq!(relative(&sub, "b.txt").unwrap(), "sub/b.txt");
+        assert_eq!(relative(&sub, "../a.txt").unwrap(), "a.txt");
+        assert_eq!(relative(&sub, ".").unwrap(), "sub");
+        assert_eq!(relative(dir.path(), ".").unwrap(), "");
+        // Deleted files are resolved through their parent
+        assert_eq!(relative(&sub, "gone/c.txt").unwrap(), "sub/gone/c.txt");
+        assert!(relative(dir.path(), "..").is_err());
+    }
 }
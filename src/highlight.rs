@@ -0,0 +1,161 @@
+use ratatui::text::Span;
+
+use crate::git::FileDiff;
+
+/// Theme used when none is configured
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+/// Syntax-colored spans for every hunk line, indexed like `FileDiff::hunks[..].lines[..]`.
+/// Spans only carry foreground colors and font modifiers; lines without colors are empty.
+#[derive(Debug, Clone, Default)]
+pub struct DiffHighlights {
+    pub hunks: Vec<Vec<Vec<Span<'static>>>>,
+}
+
+impl DiffHighlights {
+    /// Approximate memory used by the spans, for cache accounting
+    pub fn size(&self) -> usize {
+        self.hunks
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|span| span.content.len() + std::mem::size_of::<Span>())
+            .sum()
+    }
+
+    pub fn line(&self, hunk: usize, line: usize) -> Option<&[Span<'static>]> {
+        self.hunks
+            .get(hunk)
+            .and_then(|lines| lines.get(line))
+            .filter(|spans| !spans.is_empty())
+            .map(Vec::as_slice)
+    }
+}
+
+/// Highlight the lines of `diff` as the language of `path`. Returns `None` when the
+/// language isn't recognized, the theme doesn't exist or highlighting is compiled out.
+#[cfg(feature = "syntax")]
+pub fn highlight(path: &str, diff: &FileDiff, theme: &str) -> Option<DiffHighlights> {
+    imp::highlight(path, diff, theme)
+}
+
+#[cfg(not(feature = "syntax"))]
+pub fn highlight(_path: &str, _diff: &FileDiff, _theme: &str) -> Option<DiffHighlights> {
+    None
+}
+
+/// Whether `theme` names a bundled theme (always true without syntax highlighting)
+#[cfg(feature = "syntax")]
+pub fn theme_exists(theme: &str) -> bool {
+    imp::themes().themes.contains_key(theme)
+}
+
+#[cfg(not(feature = "syntax"))]
+pub fn theme_exists(_theme: &str) -> bool {
+    true
+}
+
+#[cfg(feature = "syntax")]
+mod imp {
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    use ratatui::style::{Color, Modifier, Style};
+    use ratatui::text::Span;
+    use syntect::easy::HighlightLines;
+    use syntect::highlighting::{FontStyle, Theme, ThemeSet};
+    use syntect::parsing::{SyntaxReference, SyntaxSet};
+
+    use super::DiffHighlights;
+    use crate::git::{FileDiff, LineKind};
+
+    /// Diffs larger than this are shown without syntax colors to keep them responsive
+    const MAX_HIGHLIGHT_BYTES: usize = 512 * 1024;
+
+    // Loading the bundled definitions takes a moment, so it happens on first use
+    fn syntaxes() -> &'static SyntaxSet {
+        static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+        SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+    }
+
+    pub(super) fn themes() -> &'static ThemeSet {
+        static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+        THEMES.get_or_init(ThemeSet::load_defaults)
+    }
+
+    fn find_syntax(path: &str) -> Option<&'static SyntaxReference> {
+        let path = Path::new(path);
+        let syntaxes = syntaxes();
+        path.extension()
+            .and_then(|ext| syntaxes.find_syntax_by_extension(&ext.to_string_lossy()))
+            .or_else(|| {
+                // Files like `Makefile` or `Dockerfile` are recognized by name
+                path.file_name()
+                    .and_then(|name| syntaxes.find_syntax_by_extension(&name.to_string_lossy()))
+            })
+    }
+
+    pub(super) fn highlight(path: &str, diff: &FileDiff, theme: &str) -> Option<DiffHighlights> {
+        if diff.size() > MAX_HIGHLIGHT_BYTES {
+            return None;
+        }
+        let syntax = find_syntax(path)?;
+        let theme: &Theme = themes().themes.get(theme)?;
+
+        // The old and new versions are parsed as separate streams so that
+        // multi-line constructs such as comments are tracked on each side
+        let mut old = HighlightLines::new(syntax, theme);
+        let mut new = HighlightLines::new(syntax, theme);
+        let hunks = diff
+            .hunks
+            .iter()
+            .map(|hunk| {
+                hunk.lines
+                    .iter()
+                    .map(|line| {
+                        let content = format!("{}\n", line.content);
+                        match line.kind {
+                            LineKind::Context => {
+                                let _ = old.highlight_line(&content, syntaxes());
+                                spans(&mut new, &content)
+                            }
+                            LineKind::Removed => spans(&mut old, &content),
+                            LineKind::Added => spans(&mut new, &content),
+                            LineKind::Marker => Vec::new(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        Some(DiffHighlights { hunks })
+    }
+
+    fn spans(highlighter: &mut HighlightLines, content: &str) -> Vec<Span<'static>> {
+        let Ok(regions) = highlighter.highlight_line(content, syntaxes()) else {
+            return Vec::new();
+        };
+        regions
+            .into_iter()
+            .filter_map(|(style, text)| {
+                let text = text.trim_end_matches('\n');
+                (!text.is_empty()).then(|| Span::styled(text.to_string(), convert_style(style)))
+            })
+            .collect()
+    }
+
+    /// Foreground and font style only; the background is left to the diff colors
+    fn convert_style(style: syntect::highlighting::Style) -> Style {
+        let fg = style.foreground;
+        let mut converted = Style::default().fg(Color::Rgb(fg.r, fg.g, fg.b));
+        if style.font_style.contains(FontStyle::BOLD) {
+            converted = converted.add_modifier(Modifier::BOLD);
+        }
+        if style.font_style.contains(FontStyle::ITALIC) {
+            converted = converted.add_modifier(Modifier::ITALIC);
+        }
+        if style.font_style.contains(FontStyle::UNDERLINE) {
+            converted = converted.add_modifier(Modifier::UNDERLINED);
+        }
+        converted
+    }
+}
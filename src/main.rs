@@ -1,11 +1,24 @@
 mod app;
+mod cache;
+mod clipboard;
 mod config;
 mod fuzzy;
 mod git;
+mod highlight;
+mod moved;
+mod prefetch;
+mod render;
+mod words;
 
 use anyhow::Result;
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, ValueEnum};
+use std::io::{self, Write};
 use std::panic;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use config::Config;
+use git::{FileStatus, GitDiffSettings, Repository};
 
 /// A terminal-based Git diff viewer with fuzzy search
 #[derive(Parser)]
@@ -24,28 +37,79 @@ KEYBINDINGS:
         k/Up      Move to previous file
         Enter     View diff of selected file
         e         Open file in editor
+        Space     Mark/unmark the file for export
+        x         Export the marked files and hunks (or the file) as a patch
         /         Start search mode
+        r         Refresh the list of changed files
+        Esc       Return to parent repository (inside a submodule)
         q         Quit
 
     Diff View:
-        j/Down    Scroll down
-        k/Up      Scroll up
+        j/Down    Move the cursor down
+        k/Up      Move the cursor up
+        v         Start/stop selecting lines from the cursor
+        V         Select the hunk under the cursor
+        y         Copy the selected lines (or the cursor line) as a patch
+        Y         Copy the hunk under the cursor
+        P         Copy the whole file's patch
+        Space     Mark/unmark the selected hunks (or the hunk) for export
+        x         Export the marked files and hunks (or the file) as a patch
         d/PgDn    Scroll down 20 lines
         u/PgUp    Scroll up 20 lines
         g/Home    Go to top
         G/End     Go to bottom
-        e         Open file in editor
-        Esc       Return to file list
+        h/Left    Scroll left
+        l/Right   Scroll right
+        W         Toggle wrapping long lines
+        #         Toggle old/new line numbers
+        n/N       Show the next/previous file
+        ]/[       Go to the next/previous hunk
+        K/J       Show more lines above/below the hunk under the cursor
+        Tab       Fold/unfold the hunk under the cursor
+        S-Tab     Fold/unfold all hunks
+        s         Toggle side-by-side/unified layout
+        i         Toggle word diff/unified layout
+        a         Cycle the diff algorithm (myers/minimal/patience/histogram)
+        w         Cycle whitespace handling (show/ignore all/at eol/amount/blank lines)
+        +/-       Show more/less context
+        F         Toggle showing the whole file
+        m         Jump to where the moved block at the cursor went (or came from)
+        e         Open file in editor at the cursor line
+        L         Load the full output of a truncated diff
+        r         Refresh (reloads the diff if the file changed)
+        Enter     Open submodule (on a submodule summary)
+        Esc       Cancel the selection, or return to file list
         q         Quit
 ")]
 struct Cli {
+    /// Run as if started in <DIR> instead of the current directory
+    #[arg(short = 'C', value_name = "DIR", default_value = ".")]
+    directory: PathBuf,
+
+    /// Print the changes instead of starting the viewer
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    output: Option<OutputFormat>,
+
+    /// Files or directories to limit --output to
+    #[arg(value_name = "PATH", requires = "output")]
+    paths: Vec<PathBuf>,
+
     /// Also show help (alias for -h)
     #[arg(short = 'H', long = "Help", hide = true, action = ArgAction::Help)]
     help_alias: (),
 }
 
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// A patch that `git apply` accepts
+    Patch,
+}
+
 fn main() -> Result<()> {
-    let _cli = Cli::parse();
+    let cli = Cli::parse();
+    if let Some(OutputFormat::Patch) = cli.output {
+        return print_patch(&cli);
+    }
     // パニックハンドラーを設定して、パニック時にターミナルを復元する
     let original_hook = panic::take_hook();
     panic::set_hook(Box::new(move |panic_info| {
@@ -53,11 +117,132 @@ fn main() -> Result<()> {
         original_hook(panic_info);
     }));
 
-    let app = app::App::new()?;
+    let app = app::App::new(&cli.directory)?;
 
     let terminal = ratatui::init();
+    // バックグラウンドのワーカーのパニックは捕捉されるため、画面を壊さないよう無視する
+    let terminal_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |panic_info| {
+        if thread::current().name() == Some("main") {
+            terminal_hook(panic_info);
+        }
+    }));
     let result = app.run(terminal);
     ratatui::restore();
 
     result
 }
+
+/// Write the patch of the changed files under `cli.paths` (all of them by default) to stdout
+fn print_patch(cli: &Cli) -> Result<()> {
+    let config = Config::load();
+    let repository = Repository::discover(&cli.directory)?;
+    let mut settings = repository.diff_settings();
+    if let Some(algorithm) = config.diff.algorithm {
+        settings.algorithm = algorithm;
+    }
+    write_patch(
+        &repository,
+        &cli.directory,
+        &cli.paths,
+        &settings,
+        &mut io::stdout().lock(),
+    )
+}
+
+/// Write the patch of the changed files under `paths`, given relative to `directory`
+fn write_patch(
+    repository: &Repository,
+    directory: &Path,
+    paths: &[PathBuf],
+    settings: &GitDiffSettings,
+    out: &mut impl Write,
+) -> Result<()> {
+    let filters = paths
+        .iter()
+        .map(|path| repository.relative_path(directory, path))
+        .collect::<Result<Vec<_>>>()?;
+    let files: Vec<String> = repository
+        .get_changed_files()?
+        .into_iter()
+        .filter(|file| file.status != FileStatus::Submodule)
+        .map(|file| file.path)
+        .filter(|path| {
+            filters.is_empty()
+                || filters.iter().any(|filter| {
+                    filter.is_empty() || path == filter || path.starts_with(&format!("{filter}/"))
+                })
+        })
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    for (_, patch) in repository.export_diff(&files, settings)? {
+        out.write_all(&patch)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Paths in the `diff --git` lines of a patch
+    fn patch_files(patch: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(patch)
+            .lines()
+            .filter_map(|line| line.strip_prefix("diff --git a/"))
+            .filter_map(|names| names.split(' ').next())
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn write_patch_limits_to_paths() {
+        let dir = git::repo_with_files(&[
+            ("a.txt", "a\n"),
+            ("src/b.rs", "b\n"),
+            ("src/c.rs", "c\n"),
+            ("srcs/d.rs", "d\n"),
+            ("latin1.txt", "cafe\n"),
+        ]);
+        for path in ["a.txt", "src/b.rs", "src/c.rs", "srcs/d.rs"] {
+            fs::write(dir.path().join(path), "changed\n").unwrap();
+        }
+        // "café" in Latin-1, which isn't valid UTF-8
+        fs::write(dir.path().join("latin1.txt"), b"caf\xe9\n").unwrap();
+        let repository = Repository::discover(dir.path()).unwrap();
+        let settings = repository.diff_settings();
+        let patch = |directory: &Path, paths: &[&str]| {
+            let paths: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+            let mut out = Vec::new();
+            write_patch(&repository, directory, &paths, &settings, &mut out).unwrap();
+            patch_files(&out)
+        };
+
+        assert_eq!(
+            patch(dir.path(), &[]),
+            ["a.txt", "latin1.txt", "src/b.rs", "src/c.rs", "srcs/d.rs"]
+        );
+        assert_eq!(patch(dir.path(), &["src"]), ["src/b.rs", "src/c.rs"]);
+        assert_eq!(
+            patch(dir.path(), &["src/c.rs", "a.txt"]),
+            ["a.txt", "src/c.rs"]
+        );
+        let src = dir.path().join("src");
+        assert_eq!(patch(&src, &["b.rs"]), ["src/b.rs"]);
+        assert_eq!(patch(&src, &["../srcs"]), ["srcs/d.rs"]);
+        assert!(patch(dir.path(), &["missing"]).is_empty());
+
+        let mut out = Vec::new();
+        write_patch(&repository, dir.path(), &[], &settings, &mut out).unwrap();
+        let contains = |text: &[u8]| out.windows(text.len()).any(|window| window == text);
+        assert!(contains(
+            b"--- a/a.txt\n+++ b/a.txt\n@@ -1 +1 @@\n-a\n+changed\n"
+        ));
+        // The file's bytes are kept as they are
+        assert!(contains(b"@@ -1 +1 @@\n-cafe\n+caf\xe9\n"));
+    }
+}
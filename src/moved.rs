@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::git::{DiffLine, FileDiff, FileStatus, GitDiffSettings, LineKind, Repository};
+
+/// Blocks with fewer alphanumeric characters than this aren't considered moved,
+/// so that braces and blank lines don't light up everywhere (git uses the same limit)
+const MIN_ALNUM_COUNT: usize = 20;
+
+/// Destinations tried for each line; lines repeated more often than this (such as
+/// `return None;`) only match their first occurrences
+const MAX_CANDIDATES: usize = 100;
+
+/// Changesets with more changed lines than this aren't searched for moves
+const MAX_CHANGED_LINES: usize = 200_000;
+
+/// Changesets whose files add up to more than this aren't even diffed to look for moves
+const MAX_CHANGESET_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The other end of a moved line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Move {
+    /// Index of the file in `MovedLines::path`
+    pub file: usize,
+    /// Line number in the new version for removed lines, in the old version for added lines
+    pub lineno: u32,
+    /// Whether to use the alternative color, which tells adjacent blocks apart
+    pub alt: bool,
+}
+
+/// Moved lines of one file
+#[derive(Debug, Default)]
+pub struct FileMoves {
+    /// Removed lines by their old line number
+    old: HashMap<u32, Move>,
+    /// Added lines by their new line number
+    new: HashMap<u32, Move>,
+}
+
+impl FileMoves {
+    /// Where `line` moved to (removed lines) or from (added lines)
+    pub fn line(&self, line: &DiffLine) -> Option<&Move> {
+        match line.kind {
+            LineKind::Removed => self.moved_out(line.old_lineno?),
+            LineKind::Added => self.moved_in(line.new_lineno?),
+            LineKind::Context | LineKind::Marker => None,
+        }
+    }
+
+    /// Where the removed line `old_lineno` moved to
+    pub fn moved_out(&self, old_lineno: u32) -> Option<&Move> {
+        self.old.get(&old_lineno)
+    }
+
+    /// Where the added line `new_lineno` moved from
+    pub fn moved_in(&self, new_lineno: u32) -> Option<&Move> {
+        self.new.get(&new_lineno)
+    }
+}
+
+/// A search for moved lines running in the background, cancelled when dropped
+pub struct MovedSearch {
+    result: mpsc::Receiver<MovedLines>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl MovedSearch {
+    /// Start looking for moved lines in the changeset of the repository at `workdir`
+    pub fn start(workdir: PathBuf, settings: GitDiffSettings) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sender, result) = mpsc::channel();
+        let flag = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let moved = Repository::discover(&workdir)
+                .map(|repository| MovedLines::find(&repository, &settings, &flag))
+                .unwrap_or_default();
+            // The search was abandoned if nobody is listening
+            let _ = sender.send(moved);
+        });
+        Self { result, cancel }
+    }
+
+    /// Wait up to `timeout` for the moved lines
+    pub fn wait(&self, timeout: Duration) -> Result<MovedLines, RecvTimeoutError> {
+        self.result.recv_timeout(timeout)
+    }
+}
+
+impl Drop for MovedSearch {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Lines that were removed in one place and added in another across the whole
+/// changeset, like `git diff --color-moved`
+#[derive(Debug, Default)]
+pub struct MovedLines {
+    paths: Vec<String>,
+    files: Vec<FileMoves>,
+}
+
+impl MovedLines {
+    pub fn file(&self, path: &str) -> Option<&FileMoves> {
+        let idx = self.paths.iter().position(|p| p == path)?;
+        self.files.get(idx)
+    }
+
+    pub fn path(&self, file: usize) -> &str {
+        &self.paths[file]
+    }
+
+    /// Diff the changeset of `repository` with `settings`, as the built-in diff shows
+    /// it, and find its moved lines. Nothing is found in changesets too large to diff,
+    /// or once `cancel` is set.
+    pub fn find(repository: &Repository, settings: &GitDiffSettings, cancel: &AtomicBool) -> Self {
+        let size: u64 = repository
+            .get_changed_files()
+            .unwrap_or_default()
+            .iter()
+            .filter(|file| file.status != FileStatus::Submodule)
+            .map(|file| repository.version_size(&repository.file_version(&file.path)))
+            .sum();
+        if size > MAX_CHANGESET_BYTES || cancel.load(Ordering::Relaxed) {
+            return Self::default();
+        }
+
+        // Only the changed lines matter
+        let mut settings = settings.clone();
+        settings.context_lines = 0;
+        settings.inter_hunk_context = 0;
+        let diffs = repository
+            .changeset_diff(&settings, cancel)
+            .unwrap_or_default();
+        if cancel.load(Ordering::Relaxed) {
+            return Self::default();
+        }
+        Self::detect(&diffs)
+    }
+
+    /// Find blocks of removed lines that reappear as added lines, in the same or
+    /// another file. `diffs` should be computed without context lines.
+    pub fn detect(diffs: &[(String, FileDiff)]) -> Self {
+        let mut moved = MovedLines {
+            paths: diffs.iter().map(|(path, _)| path.clone()).collect(),
+            files: diffs.iter().map(|_| FileMoves::default()).collect(),
+        };
+
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for (file, (_, diff)) in diffs.iter().enumerate() {
+            for line in diff.hunks.iter().flat_map(|hunk| &hunk.lines) {
+                match (line.kind, line.old_lineno, line.new_lineno) {
+                    (LineKind::Removed, Some(lineno), _) => {
+                        removed.push(Side::new(file, lineno, line))
+                    }
+                    (LineKind::Added, _, Some(lineno)) => added.push(Side::new(file, lineno, line)),
+                    _ => {}
+                }
+            }
+        }
+        if removed.len() + added.len() > MAX_CHANGED_LINES {
+            return moved;
+        }
+
+        let added_at: HashMap<(usize, u32), usize> = added
+            .iter()
+            .enumerate()
+            .map(|(idx, side)| ((side.file, side.lineno), idx))
+            .collect();
+        let mut added_by_content: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (idx, side) in added.iter().enumerate() {
+            if side.alnum_count > 0 {
+                let candidates = added_by_content.entry(side.content).or_default();
+                if candidates.len() < MAX_CANDIDATES {
+                    candidates.push(idx);
+                }
+            }
+        }
+
+        let mut claimed = vec![false; added.len()];
+        let mut blocks = Vec::new();
+        let mut start = 0;
+        while start < removed.len() {
+            // The longest run of consecutive lines that reappears consecutively
+            let mut best: Option<(usize, usize)> = None;
+            for &candidate in added_by_content
+                .get(removed[start].content)
+                .into_iter()
+                .flatten()
+            {
+                let target = &added[candidate];
+                let len = (0u32..)
+                    .take_while(|&offset| {
+                        let (Some(old), Some(&new)) = (
+                            removed.get(start + offset as usize),
+                            added_at.get(&(target.file, target.lineno + offset)),
+                        ) else {
+                            return false;
+                        };
+                        !claimed[new]
+                            && old.file == removed[start].file
+                            && old.lineno == removed[start].lineno + offset
+                            && old.content == added[new].content
+                    })
+                    .count();
+                if len > best.map_or(0, |(_, best_len)| best_len) {
+                    best = Some((candidate, len));
+                }
+            }
+
+            let Some((candidate, len)) = best else {
+                start += 1;
+                continue;
+            };
+            let alnum_count: usize = removed[start..start + len]
+                .iter()
+                .map(|side| side.alnum_count)
+                .sum();
+            if alnum_count < MIN_ALNUM_COUNT {
+                start += 1;
+                continue;
+            }
+            let (source, target) = (&removed[start], &added[candidate]);
+            for offset in 0..len as u32 {
+                claimed[added_at[&(target.file, target.lineno + offset)]] = true;
+            }
+            blocks.push(Block {
+                old: (source.file, source.lineno),
+                new: (target.file, target.lineno),
+                len: len as u32,
+            });
+            start += len;
+        }
+
+        let old_alt = alternation(blocks.iter().map(|block| (block.old, block.len)));
+        let new_alt = alternation(blocks.iter().map(|block| (block.new, block.len)));
+        for (idx, block) in blocks.iter().enumerate() {
+            let ((old_file, old_start), (new_file, new_start)) = (block.old, block.new);
+            for offset in 0..block.len {
+                moved.files[old_file].old.insert(
+                    old_start + offset,
+                    Move {
+                        file: new_file,
+                        lineno: new_start + offset,
+                        alt: old_alt[idx],
+                    },
+                );
+                moved.files[new_file].new.insert(
+                    new_start + offset,
+                    Move {
+                        file: old_file,
+                        lineno: old_start + offset,
+                        alt: new_alt[idx],
+                    },
+                );
+            }
+        }
+        moved
+    }
+}
+
+/// A changed line, on the side of the diff it exists on
+struct Side<'a> {
+    file: usize,
+    lineno: u32,
+    content: &'a str,
+    alnum_count: usize,
+}
+
+impl<'a> Side<'a> {
+    fn new(file: usize, lineno: u32, line: &'a DiffLine) -> Self {
+        Self {
+            file,
+            lineno,
+            content: &line.content,
+            alnum_count: line.content.chars().filter(|c| c.is_alphanumeric()).count(),
+        }
+    }
+}
+
+/// Lines `old` to `old + len` moved to `new` to `new + len`, as (file, line number)
+struct Block {
+    old: (usize, u32),
+    new: (usize, u32),
+    len: u32,
+}
+
+/// Whether each block (given as its start and length) should use the alternative
+/// color: blocks directly following another one alternate, like git's zebra mode
+fn alternation(blocks: impl Iterator<Item = ((usize, u32), u32)>) -> Vec<bool> {
+    let blocks: Vec<_> = blocks.collect();
+    let mut order: Vec<usize> = (0..blocks.len()).collect();
+    order.sort_by_key(|&idx| blocks[idx].0);
+
+    let mut alt = vec![false; blocks.len()];
+    let mut previous: Option<usize> = None;
+    for idx in order {
+        if let Some(prev) = previous {
+            let ((prev_file, prev_start), prev_len) = blocks[prev];
+            let (file, start) = blocks[idx].0;
+            alt[idx] = file == prev_file && start == prev_start + prev_len && !alt[prev];
+        }
+        previous = Some(idx);
+    }
+    alt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::Hunk;
+
+    /// A diff of one hunk whose lines start at the given old and new line numbers
+    fn diff(old_start: u32, new_start: u32, lines: &[(LineKind, &str)]) -> FileDiff {
+        let (mut old, mut new) = (old_start, new_start);
+        let lines = lines
+            .iter()
+            .map(|&(kind, content)| {
+                let (old_lineno, new_lineno) = match kind {
+                    LineKind::Removed => (Some(old), None),
+                    LineKind::Added => (None, Some(new)),
+                    _ => (Some(old), Some(new)),
+                };
+                old += u32::from(old_lineno.is_some());
+                new += u32::from(new_lineno.is_some());
+                DiffLine {
+                    kind,
+                    old_lineno,
+                    new_lineno,
+                    content: content.to_string(),
+                }
+            })
+            .collect();
+        FileDiff {
+            header: Vec::new(),
+            hunks: vec![Hunk {
+                header: String::new(),
+                lines,
+            }],
+        }
+    }
+
+    const BLOCK: [&str; 3] = [
+        "fn moved_function(value: usize) -> usize {",
+        "    value * 2",
+        "}",
+    ];
+
+    #[test]
+    fn detects_block_moved_to_another_file() {
+        let removed: Vec<_> = BLOCK.iter().map(|&l| (LineKind::Removed, l)).collect();
+        let added: Vec<_> = BLOCK.iter().map(|&l| (LineKind::Added, l)).collect();
+        let diffs = [
+            ("a.rs".to_string(), diff(5, 5, &removed)),
+            ("b.rs".to_string(), diff(1, 10, &added)),
+        ];
+        let moved = MovedLines::detect(&diffs);
+
+        let a = moved.file("a.rs").unwrap();
+        let b = moved.file("b.rs").unwrap();
+        for offset in 0..3 {
+            let out = a.moved_out(5 + offset).unwrap();
+            assert_eq!((moved.path(out.file), out.lineno), ("b.rs", 10 + offset));
+            let into = b.moved_in(10 + offset).unwrap();
+            assert_eq!((moved.path(into.file), into.lineno), ("a.rs", 5 + offset));
+        }
+        assert!(a.moved_out(8).is_none());
+    }
+
+    #[test]
+    fn ignores_blocks_with_little_text() {
+        let diffs = [(
+            "a.rs".to_string(),
+            diff(
+                1,
+                1,
+                &[
+                    (LineKind::Removed, "}"),
+                    (LineKind::Removed, "x += 1;"),
+                    (LineKind::Context, "fn f() {"),
+                    (LineKind::Added, "}"),
+                    (LineKind::Added, "x += 1;"),
+                ],
+            ),
+        )];
+        let moved = MovedLines::detect(&diffs);
+        let file = moved.file("a.rs").unwrap();
+        assert!(file.moved_out(1).is_none() && file.moved_out(2).is_none());
+    }
+
+    #[test]
+    fn adjacent_blocks_alternate_colors() {
+        assert_eq!(
+            alternation([((0, 1), 2), ((0, 3), 2), ((0, 5), 1), ((0, 9), 1)].into_iter()),
+            [false, true, false, false]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::DiffKey;
+use crate::config::DiffConfig;
+use crate::git::{get_diff, DiffError, DiffRequest, DiffResult, Repository};
+
+/// Number of diffs computed speculatively at the same time
+const WORKERS: usize = 2;
+
+struct Job {
+    key: DiffKey,
+    workdir: PathBuf,
+    width: u16,
+    height: u16,
+    config: Arc<DiffConfig>,
+    cancel: Arc<AtomicBool>,
+}
+
+type JobResult = (DiffKey, Result<DiffResult, DiffError>);
+
+/// Computes diffs on background threads so they are ready before they are opened
+pub struct Prefetcher {
+    jobs: mpsc::Sender<Job>,
+    results: mpsc::Receiver<JobResult>,
+    /// Cancellation flags of jobs that are queued or running
+    in_flight: HashMap<DiffKey, Arc<AtomicBool>>,
+}
+
+impl Prefetcher {
+    pub fn new() -> Self {
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        for _ in 0..WORKERS {
+            let job_rx = Arc::clone(&job_rx);
+            let result_tx = result_tx.clone();
+            thread::spawn(move || loop {
+                let job = match job_rx.lock() {
+                    Ok(job_rx) => match job_rx.recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    },
+                    Err(_) => return,
+                };
+                // A panic must still report back, or the job would stay in flight forever
+                let result = panic::catch_unwind(AssertUnwindSafe(|| run_job(&job)))
+                    .unwrap_or_else(|_| Some(Err(DiffError::Git("diff worker panicked".into()))));
+                if let Some(result) = result {
+                    if result_tx.send((job.key, result)).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        Self {
+            jobs: job_tx,
+            results: result_rx,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn request(
+        &mut self,
+        key: DiffKey,
+        workdir: PathBuf,
+        width: u16,
+        height: u16,
+        config: Arc<DiffConfig>,
+    ) {
+        if self.in_flight.contains_key(&key) {
+            return;
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        let job = Job {
+            key: key.clone(),
+            workdir,
+            width,
+            height,
+            config,
+            cancel: Arc::clone(&cancel),
+        };
+        if self.jobs.send(job).is_ok() {
+            self.in_flight.insert(key, cancel);
+        }
+    }
+
+    /// Cancel every queued or running job for which `keep` returns false
+    pub fn retain(&mut self, mut keep: impl FnMut(&DiffKey) -> bool) {
+        self.in_flight.retain(|key, cancel| {
+            let keep = keep(key);
+            if !keep {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            keep
+        });
+    }
+
+    pub fn cancel_all(&mut self) {
+        self.retain(|_| false);
+    }
+
+    pub fn is_pending(&self, key: &DiffKey) -> bool {
+        self.in_flight.contains_key(key)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.in_flight.is_empty()
+    }
+
+    /// Take a finished result without blocking. With a `timeout`, wait up to that long for one.
+    pub fn next_result(&mut self, timeout: Option<Duration>) -> Option<JobResult> {
+        loop {
+            let received = match timeout {
+                Some(timeout) => self.results.recv_timeout(timeout).ok(),
+                None => self.results.try_recv().ok(),
+            };
+            let (key, result) = received?;
+            // Results of cancelled jobs that finished anyway are stale; skip them
+            if self.in_flight.remove(&key).is_some() {
+                return Some((key, result));
+            }
+        }
+    }
+}
+
+fn run_job(job: &Job) -> Option<Result<DiffResult, DiffError>> {
+    if job.cancel.load(Ordering::Relaxed) {
+        return None;
+    }
+    let result = match Repository::discover(&job.workdir) {
+        Ok(repository) => get_diff(
+            &repository,
+            &DiffRequest {
+                path: &job.key.path,
+                width: job.width,
+                height: job.height,
+                load_full: job.key.load_full,
+                overrides: job.key.overrides,
+                cancel: Some(&job.cancel),
+            },
+            &job.config,
+        ),
+        Err(e) => Err(DiffError::Git(format!("{e:#}"))),
+    };
+    match result {
+        Err(DiffError::Cancelled) => None,
+        result => Some(result),
+    }
+}
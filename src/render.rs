@@ -0,0 +1,1033 @@
+use std::collections::HashSet;
+use std::ops::Range;
+
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+};
+use unicode_width::UnicodeWidthChar;
+
+use crate::git::{self, DiffLine, FileDiff, GitDiffSettings, Hunk, LineKind};
+use crate::highlight::DiffHighlights;
+use crate::moved::{FileMoves, Move};
+use crate::words::{self, WordChange};
+
+const TAB_WIDTH: usize = 4;
+
+/// Column between the old and new side of a split diff
+const SEPARATOR: &str = "│";
+
+/// Shown in the first column when a line continues to the left of the view
+const CUT_LEFT_MARKER: char = '‹';
+
+/// Shown in the last column when a line continues past the right of the view
+const CUT_RIGHT_MARKER: char = '›';
+
+/// How the built-in diff is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffLayout {
+    #[default]
+    Unified,
+    /// Old version on the left, new version on the right
+    SideBySide,
+    /// Changed words inline, like `git diff --word-diff`
+    WordDiff,
+}
+
+impl DiffLayout {
+    /// Switch to `layout`, or back to unified if it is already active
+    pub fn toggle(self, layout: DiffLayout) -> Self {
+        if self == layout {
+            DiffLayout::Unified
+        } else {
+            layout
+        }
+    }
+}
+
+/// Rendered diff lines, along with the diff lines each of them shows
+#[derive(Debug, Clone, Default)]
+pub struct RenderedDiff {
+    pub lines: Vec<Line<'static>>,
+    /// One entry per line
+    pub rows: Vec<RowSource>,
+    /// Hunk that rows pushed now belong to
+    hunk: Option<usize>,
+}
+
+impl RenderedDiff {
+    fn push(&mut self, line: Line<'static>, source: RowSource) {
+        self.lines.push(line);
+        self.rows.push(RowSource {
+            hunk: self.hunk,
+            ..source
+        });
+    }
+
+    /// Begin hunk `idx` with its header, or with a one-line summary when it is in
+    /// `folded`. Returns whether the hunk's lines should follow.
+    fn start_hunk(
+        &mut self,
+        idx: usize,
+        hunk: &Hunk,
+        styles: &DiffStyles,
+        folded: &HashSet<usize>,
+    ) -> bool {
+        self.hunk = Some(idx);
+        let mut header = hunk_header(&hunk.header, styles);
+        if !folded.contains(&idx) {
+            self.push(header, RowSource::default());
+            return true;
+        }
+
+        let count = |kind| hunk.lines.iter().filter(|line| line.kind == kind).count();
+        header.spans.extend([
+            Span::styled(" … ", Style::default().add_modifier(Modifier::DIM)),
+            Span::styled(format!("+{}", count(LineKind::Added)), styles.new),
+            Span::raw(" "),
+            Span::styled(format!("-{}", count(LineKind::Removed)), styles.old),
+        ]);
+        self.push(header, RowSource::default());
+        false
+    }
+}
+
+/// Where a rendered row comes from: its hunk, and the line numbers it shows on either side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowSource {
+    /// Index into `FileDiff::hunks`, `None` for the file header
+    pub hunk: Option<usize>,
+    pub old_lineno: Option<u32>,
+    pub new_lineno: Option<u32>,
+}
+
+/// Line numbers of diff output from another tool, worked out from the `@@` hunk headers
+/// of unified diff lines. Rows outside hunks, and all rows of output in other formats,
+/// get no numbers.
+pub fn text_rows(lines: &[Line<'static>]) -> Vec<RowSource> {
+    let mut rows = Vec::with_capacity(lines.len());
+    let mut hunk: Option<usize> = None;
+    // Next line number and lines left in the hunk, on each side
+    let (mut old, mut new) = ((0, 0), (0, 0));
+
+    for line in lines {
+        let text: String = line
+            .spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect();
+        if let Some(ranges) = text
+            .starts_with("@@")
+            .then(|| git::parse_hunk_ranges(&text))
+            .flatten()
+        {
+            hunk = Some(hunk.map_or(0, |idx| idx + 1));
+            (old, new) = ranges;
+            rows.push(RowSource {
+                hunk,
+                ..RowSource::default()
+            });
+            continue;
+        }
+        if old.1 == 0 && new.1 == 0 && !text.starts_with('\\') {
+            rows.push(RowSource::default());
+            continue;
+        }
+
+        let mut row = RowSource {
+            hunk,
+            ..RowSource::default()
+        };
+        let (take_old, take_new) = match text.chars().next() {
+            Some('-') => (true, false),
+            Some('+') => (false, true),
+            Some('\\') => (false, false),
+            _ => (true, true),
+        };
+        if take_old {
+            row.old_lineno = Some(old.0);
+            old = (old.0 + 1, old.1.saturating_sub(1));
+        }
+        if take_new {
+            row.new_lineno = Some(new.0);
+            new = (new.0 + 1, new.1.saturating_sub(1));
+        }
+        rows.push(row);
+    }
+    rows
+}
+
+/// Column of old and new line numbers shown left of each row
+#[derive(Debug, Clone, Copy)]
+pub struct Gutter {
+    /// Digits reserved for each line number
+    digits: usize,
+}
+
+impl Gutter {
+    /// Gutter wide enough for the line numbers in `rows`, or `None` if they have none
+    pub fn new(rows: &[RowSource]) -> Option<Self> {
+        let max_lineno = rows
+            .iter()
+            .flat_map(|row| row.old_lineno.max(row.new_lineno))
+            .max()?;
+        Some(Self {
+            digits: max_lineno.to_string().len(),
+        })
+    }
+
+    /// Display columns taken up by the gutter
+    pub fn width(&self) -> usize {
+        self.digits * 2 + 2
+    }
+
+    pub fn span(&self, row: Option<&RowSource>) -> Span<'static> {
+        let number = |lineno: Option<u32>| lineno.map(|n| n.to_string()).unwrap_or_default();
+        let (old, new) = row.map_or((None, None), |row| (row.old_lineno, row.new_lineno));
+        Span::styled(
+            format!(
+                "{:>digits$} {:>digits$}{SEPARATOR}",
+                number(old),
+                number(new),
+                digits = self.digits
+            ),
+            Style::default().add_modifier(Modifier::DIM),
+        )
+    }
+}
+
+/// Styles for each part of a diff, following git's `color.diff.<slot>` names
+#[derive(Debug, Clone)]
+pub struct DiffStyles {
+    pub context: Style,
+    pub meta: Style,
+    pub frag: Style,
+    pub func: Style,
+    pub old: Style,
+    pub new: Style,
+    /// Background of removed lines when their text is syntax highlighted
+    pub old_syntax: Style,
+    /// Background of added lines when their text is syntax highlighted
+    pub new_syntax: Style,
+    /// Patched over changed words within removed lines
+    pub old_emph: Style,
+    /// Patched over changed words within added lines
+    pub new_emph: Style,
+    /// Changed words in removed lines when syntax highlighting is in use
+    pub old_syntax_emph: Style,
+    /// Changed words in added lines when syntax highlighting is in use
+    pub new_syntax_emph: Style,
+    /// Removed lines that were added elsewhere
+    pub old_moved: Style,
+    /// Added lines that were removed elsewhere
+    pub new_moved: Style,
+    /// Moved blocks right after another moved block use these instead
+    pub old_moved_alt: Style,
+    pub new_moved_alt: Style,
+    /// Whether syntax colors may be used at all (off when git colors are disabled)
+    pub syntax: bool,
+}
+
+impl Default for DiffStyles {
+    /// git's default diff colors
+    fn default() -> Self {
+        Self {
+            context: Style::default(),
+            meta: Style::default().add_modifier(Modifier::BOLD),
+            frag: Style::default().fg(Color::Cyan),
+            func: Style::default(),
+            old: Style::default().fg(Color::Red),
+            new: Style::default().fg(Color::Green),
+            old_syntax: Style::default().bg(Color::Indexed(52)),
+            new_syntax: Style::default().bg(Color::Indexed(22)),
+            old_emph: Style::default().add_modifier(Modifier::REVERSED),
+            new_emph: Style::default().add_modifier(Modifier::REVERSED),
+            old_syntax_emph: Style::default().bg(Color::Indexed(88)),
+            new_syntax_emph: Style::default().bg(Color::Indexed(28)),
+            old_moved: Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+            new_moved: Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+            old_moved_alt: Style::default()
+                .fg(Color::Blue)
+                .add_modifier(Modifier::BOLD),
+            new_moved_alt: Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+            syntax: true,
+        }
+    }
+}
+
+impl DiffStyles {
+    pub fn from_settings(settings: &GitDiffSettings) -> Self {
+        if !settings.color {
+            return Self {
+                context: Style::default(),
+                meta: Style::default(),
+                frag: Style::default(),
+                func: Style::default(),
+                old: Style::default(),
+                new: Style::default(),
+                old_syntax: Style::default(),
+                new_syntax: Style::default(),
+                old_emph: Style::default(),
+                new_emph: Style::default(),
+                old_syntax_emph: Style::default(),
+                new_syntax_emph: Style::default(),
+                old_moved: Style::default(),
+                new_moved: Style::default(),
+                old_moved_alt: Style::default(),
+                new_moved_alt: Style::default(),
+                syntax: false,
+            };
+        }
+
+        let mut styles = Self::default();
+        for (slot, value) in &settings.slot_colors {
+            let Some(style) = parse_git_color(value) else {
+                continue;
+            };
+            match slot.as_str() {
+                "context" | "plain" => styles.context = style,
+                "meta" => styles.meta = style,
+                "frag" => styles.frag = style,
+                "func" => styles.func = style,
+                "oldmoved" => styles.old_moved = style,
+                "newmoved" => styles.new_moved = style,
+                "oldmovedalternative" => styles.old_moved_alt = style,
+                "newmovedalternative" => styles.new_moved_alt = style,
+                "old" => {
+                    styles.old = style;
+                    if let Some(bg) = style.bg {
+                        styles.old_syntax = Style::default().bg(bg);
+                    }
+                }
+                "new" => {
+                    styles.new = style;
+                    if let Some(bg) = style.bg {
+                        styles.new_syntax = Style::default().bg(bg);
+                    }
+                }
+                _ => {}
+            }
+        }
+        styles
+    }
+}
+
+/// Parse a git color value such as `"bold red"`, `"#ff0000 ul"` or `"208 black"`.
+/// The first color is the foreground, the second the background.
+fn parse_git_color(value: &str) -> Option<Style> {
+    let mut style = Style::default();
+    let mut colors_seen = 0;
+
+    for word in value.split_whitespace() {
+        let word = word.to_ascii_lowercase();
+        if let Some(modifier) =
+            parse_attribute(word.trim_start_matches("no").trim_start_matches('-'))
+        {
+            if word.starts_with("no") {
+                style = style.remove_modifier(modifier);
+            } else {
+                style = style.add_modifier(modifier);
+            }
+            continue;
+        }
+
+        let color = parse_color(&word)?;
+        match colors_seen {
+            0 => style = style.fg(color),
+            1 => style = style.bg(color),
+            _ => return None,
+        }
+        colors_seen += 1;
+    }
+
+    Some(style)
+}
+
+fn parse_attribute(word: &str) -> Option<Modifier> {
+    match word {
+        "bold" => Some(Modifier::BOLD),
+        "dim" => Some(Modifier::DIM),
+        "ul" | "underline" => Some(Modifier::UNDERLINED),
+        "blink" => Some(Modifier::SLOW_BLINK),
+        "reverse" => Some(Modifier::REVERSED),
+        "italic" => Some(Modifier::ITALIC),
+        "strike" => Some(Modifier::CROSSED_OUT),
+        _ => None,
+    }
+}
+
+fn parse_color(word: &str) -> Option<Color> {
+    if let Some(hex) = word.strip_prefix('#') {
+        if hex.len() == 6 {
+            let value = u32::from_str_radix(hex, 16).ok()?;
+            return Some(Color::Rgb(
+                (value >> 16) as u8,
+                (value >> 8) as u8,
+                value as u8,
+            ));
+        }
+        return None;
+    }
+    if let Ok(index) = word.parse::<u8>() {
+        return Some(Color::Indexed(index));
+    }
+
+    let (bright, name) = match word.strip_prefix("bright") {
+        Some(name) => (true, name),
+        None => (false, word),
+    };
+    let color = match (name, bright) {
+        ("normal" | "default", false) => Color::Reset,
+        ("black", false) => Color::Black,
+        ("red", false) => Color::Red,
+        ("green", false) => Color::Green,
+        ("yellow", false) => Color::Yellow,
+        ("blue", false) => Color::Blue,
+        ("magenta", false) => Color::Magenta,
+        ("cyan", false) => Color::Cyan,
+        ("white", false) => Color::Gray,
+        ("black", true) => Color::DarkGray,
+        ("red", true) => Color::LightRed,
+        ("green", true) => Color::LightGreen,
+        ("yellow", true) => Color::LightYellow,
+        ("blue", true) => Color::LightBlue,
+        ("magenta", true) => Color::LightMagenta,
+        ("cyan", true) => Color::LightCyan,
+        ("white", true) => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
+
+/// Render a diff as colored unified diff lines, like `git diff --color`.
+/// With `highlights`, line text gets syntax colors over the added/removed backgrounds;
+/// lines in `moves` get the moved colors instead. Hunks in `folded` are summarized.
+pub fn unified(
+    diff: &FileDiff,
+    styles: &DiffStyles,
+    highlights: Option<&DiffHighlights>,
+    moves: Option<&FileMoves>,
+    folded: &HashSet<usize>,
+) -> RenderedDiff {
+    let mut rendered = RenderedDiff::default();
+
+    for header in &diff.header {
+        rendered.push(
+            Line::styled(expand_tabs(header), styles.meta),
+            RowSource::default(),
+        );
+    }
+
+    for (hunk_idx, hunk) in diff.hunks.iter().enumerate() {
+        if !rendered.start_hunk(hunk_idx, hunk, styles, folded) {
+            continue;
+        }
+        let emphasis = words::hunk_emphasis(hunk);
+        for (line_idx, line) in hunk.lines.iter().enumerate() {
+            let origin = match line.kind {
+                LineKind::Context => " ",
+                LineKind::Added => "+",
+                LineKind::Removed => "-",
+                LineKind::Marker => "",
+            };
+            let syntax = highlights.and_then(|h| h.line(hunk_idx, line_idx));
+            let moved = moves.and_then(|m| m.line(line));
+            let (base, text) = line_text(line, syntax, &emphasis[line_idx], moved, styles);
+            let origin_style = base.patch(line_style(line, moved, styles));
+            let mut spans = vec![Span::styled(origin, origin_style)];
+            spans.extend(text);
+            rendered.push(
+                Line::from(spans),
+                RowSource {
+                    old_lineno: line.old_lineno,
+                    new_lineno: line.new_lineno,
+                    ..RowSource::default()
+                },
+            );
+        }
+    }
+
+    rendered
+}
+
+/// The plain diff style of a line
+fn line_style(line: &DiffLine, moved: Option<&Move>, styles: &DiffStyles) -> Style {
+    match (line.kind, moved) {
+        (LineKind::Context | LineKind::Marker, _) => styles.context,
+        (LineKind::Added, None) => styles.new,
+        (LineKind::Added, Some(moved)) if moved.alt => styles.new_moved_alt,
+        (LineKind::Added, Some(_)) => styles.new_moved,
+        (LineKind::Removed, None) => styles.old,
+        (LineKind::Removed, Some(moved)) if moved.alt => styles.old_moved_alt,
+        (LineKind::Removed, Some(_)) => styles.old_moved,
+    }
+}
+
+/// Tab-expanded spans for the text of a hunk line, with syntax colors when available
+/// and the `emphasis` byte ranges (changed words) highlighted. Moved lines are plainly
+/// colored instead, as git does. Also returns the style for the rest of the line (the
+/// background behind the text).
+fn line_text(
+    line: &DiffLine,
+    syntax: Option<&[Span<'static>]>,
+    emphasis: &[Range<usize>],
+    moved: Option<&Move>,
+    styles: &DiffStyles,
+) -> (Style, Vec<Span<'static>>) {
+    if moved.is_some() {
+        let style = line_style(line, moved, styles);
+        let spans = vec![Span::styled(line.content.clone(), style)];
+        return (style, expand_tabs_in_spans(spans));
+    }
+    let (base, spans, emph) = match syntax {
+        Some(syntax) => {
+            let (base, emph) = match line.kind {
+                LineKind::Added => (styles.new_syntax, styles.new_syntax_emph),
+                LineKind::Removed => (styles.old_syntax, styles.old_syntax_emph),
+                LineKind::Context | LineKind::Marker => (Style::default(), Style::default()),
+            };
+            let spans = syntax
+                .iter()
+                .map(|span| Span::styled(span.content.clone(), base.patch(span.style)))
+                .collect();
+            (base, spans, emph)
+        }
+        None => {
+            let style = line_style(line, None, styles);
+            let emph = match line.kind {
+                LineKind::Added => styles.new_emph,
+                LineKind::Removed => styles.old_emph,
+                LineKind::Context | LineKind::Marker => Style::default(),
+            };
+            let spans = vec![Span::styled(line.content.clone(), style)];
+            (style, spans, emph)
+        }
+    };
+    let spans = emphasize(spans, emphasis, emph);
+    (base, expand_tabs_in_spans(spans))
+}
+
+/// Split `spans` at the edges of `ranges` (byte offsets into their joined text)
+/// and patch `emph` over the parts inside them
+fn emphasize(
+    spans: Vec<Span<'static>>,
+    ranges: &[Range<usize>],
+    emph: Style,
+) -> Vec<Span<'static>> {
+    if ranges.is_empty() {
+        return spans;
+    }
+    let mut out = Vec::with_capacity(spans.len() + 2 * ranges.len());
+    let mut offset = 0;
+    for span in spans {
+        let text = span.content.as_ref();
+        let end = offset + text.len();
+        let mut cuts = vec![offset, end];
+        for range in ranges {
+            cuts.extend(
+                [range.start, range.end]
+                    .into_iter()
+                    .filter(|&c| c > offset && c < end),
+            );
+        }
+        cuts.sort_unstable();
+        cuts.dedup();
+        for part in cuts.windows(2) {
+            let (start, stop) = (part[0], part[1]);
+            let inside = ranges.iter().any(|r| r.start <= start && stop <= r.end);
+            let style = if inside {
+                span.style.patch(emph)
+            } else {
+                span.style
+            };
+            out.push(Span::styled(
+                text[start - offset..stop - offset].to_string(),
+                style,
+            ));
+        }
+        offset = end;
+    }
+    out
+}
+
+/// Render a diff with the old and new versions next to each other in `width` columns.
+/// Runs of removed lines are paired with the added lines that follow them.
+pub fn side_by_side(
+    diff: &FileDiff,
+    styles: &DiffStyles,
+    highlights: Option<&DiffHighlights>,
+    moves: Option<&FileMoves>,
+    folded: &HashSet<usize>,
+    width: u16,
+) -> RenderedDiff {
+    let mut rendered = RenderedDiff::default();
+
+    for header in &diff.header {
+        rendered.push(
+            Line::styled(expand_tabs(header), styles.meta),
+            RowSource::default(),
+        );
+    }
+
+    let max_lineno = diff
+        .hunks
+        .iter()
+        .flat_map(|hunk| &hunk.lines)
+        .flat_map(|line| line.old_lineno.max(line.new_lineno))
+        .max()
+        .unwrap_or(0);
+    let columns = SplitColumns::new(width, max_lineno);
+
+    for (hunk_idx, hunk) in diff.hunks.iter().enumerate() {
+        if !rendered.start_hunk(hunk_idx, hunk, styles, folded) {
+            continue;
+        }
+
+        let emphasis = words::hunk_emphasis(hunk);
+        let mut removed: Vec<Cell> = Vec::new();
+        let mut added: Vec<Cell> = Vec::new();
+        for (line_idx, line) in hunk.lines.iter().enumerate() {
+            let syntax = highlights.and_then(|h| h.line(hunk_idx, line_idx));
+            let moved = moves.and_then(|m| m.line(line));
+            let cell = |lineno| {
+                let (base, text) = line_text(line, syntax, &emphasis[line_idx], moved, styles);
+                Cell { lineno, base, text }
+            };
+            match line.kind {
+                LineKind::Removed if added.is_empty() => removed.push(cell(line.old_lineno)),
+                LineKind::Removed => {
+                    flush_changes(&mut rendered, &mut removed, &mut added, &columns);
+                    removed.push(cell(line.old_lineno));
+                }
+                LineKind::Added => added.push(cell(line.new_lineno)),
+                // "No newline" markers belong to the side of the line before them
+                LineKind::Marker if !added.is_empty() => added.push(cell(None)),
+                LineKind::Marker if !removed.is_empty() => removed.push(cell(None)),
+                LineKind::Context | LineKind::Marker => {
+                    flush_changes(&mut rendered, &mut removed, &mut added, &columns);
+                    let source = RowSource {
+                        old_lineno: line.old_lineno,
+                        new_lineno: line.new_lineno,
+                        ..RowSource::default()
+                    };
+                    let left = columns.cell(cell(line.old_lineno));
+                    let right = columns.cell(cell(line.new_lineno));
+                    rendered.push(columns.row(left, right), source);
+                }
+            }
+        }
+        flush_changes(&mut rendered, &mut removed, &mut added, &columns);
+    }
+
+    rendered
+}
+
+/// Render a diff with changed words inline instead of whole removed and added lines,
+/// like `git diff --word-diff=color`. Suited to prose, where lines are long and wrap.
+pub fn word_diff(diff: &FileDiff, styles: &DiffStyles, folded: &HashSet<usize>) -> RenderedDiff {
+    let mut rendered = RenderedDiff::default();
+
+    for header in &diff.header {
+        rendered.push(
+            Line::styled(expand_tabs(header), styles.meta),
+            RowSource::default(),
+        );
+    }
+
+    for (hunk_idx, hunk) in diff.hunks.iter().enumerate() {
+        if !rendered.start_hunk(hunk_idx, hunk, styles, folded) {
+            continue;
+        }
+        let mut removed: Vec<&str> = Vec::new();
+        let mut added: Vec<&str> = Vec::new();
+        for line in &hunk.lines {
+            match line.kind {
+                LineKind::Removed if added.is_empty() => removed.push(&line.content),
+                LineKind::Removed => {
+                    flush_words(&mut rendered, &mut removed, &mut added, styles);
+                    removed.push(&line.content);
+                }
+                LineKind::Added => added.push(&line.content),
+                // Line endings aren't visible in this layout
+                LineKind::Marker => {}
+                LineKind::Context => {
+                    flush_words(&mut rendered, &mut removed, &mut added, styles);
+                    rendered.push(
+                        Line::styled(expand_tabs(&line.content), styles.context),
+                        RowSource {
+                            old_lineno: line.old_lineno,
+                            new_lineno: line.new_lineno,
+                            ..RowSource::default()
+                        },
+                    );
+                }
+            }
+        }
+        flush_words(&mut rendered, &mut removed, &mut added, styles);
+    }
+
+    rendered
+}
+
+/// Emit a block of removed and added lines as merged lines of changed words
+fn flush_words(
+    rendered: &mut RenderedDiff,
+    removed: &mut Vec<&str>,
+    added: &mut Vec<&str>,
+    styles: &DiffStyles,
+) {
+    if removed.is_empty() && added.is_empty() {
+        return;
+    }
+    for words in words::word_diff(removed, added) {
+        let spans = words.into_iter().map(|(change, text)| {
+            let style = match change {
+                WordChange::Equal => styles.context,
+                WordChange::Deleted => styles.old,
+                WordChange::Inserted => styles.new,
+            };
+            Span::styled(text, style)
+        });
+        // Merged lines don't correspond to a single line on either side
+        rendered.push(
+            Line::from(expand_tabs_in_spans(spans)),
+            RowSource::default(),
+        );
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// One side of a row in a split diff
+struct Cell {
+    lineno: Option<u32>,
+    /// Style of the padding after the text
+    base: Style,
+    text: Vec<Span<'static>>,
+}
+
+/// Emit a block of removed and added lines as aligned rows, padding the shorter side
+fn flush_changes(
+    rendered: &mut RenderedDiff,
+    removed: &mut Vec<Cell>,
+    added: &mut Vec<Cell>,
+    columns: &SplitColumns,
+) {
+    let rows = removed.len().max(added.len());
+    let mut removed = removed.drain(..);
+    let mut added = added.drain(..);
+    for _ in 0..rows {
+        let mut source = RowSource::default();
+        let left = match removed.next() {
+            Some(cell) => {
+                source.old_lineno = cell.lineno;
+                columns.cell(cell)
+            }
+            None => columns.blank(),
+        };
+        let right = match added.next() {
+            Some(cell) => {
+                source.new_lineno = cell.lineno;
+                columns.cell(cell)
+            }
+            None => columns.blank(),
+        };
+        rendered.push(columns.row(left, right), source);
+    }
+}
+
+/// Column widths of a split diff
+struct SplitColumns {
+    /// Digits reserved for line numbers
+    lineno_width: usize,
+    /// Width of the text after the line number, on each side
+    text_width: usize,
+}
+
+impl SplitColumns {
+    fn new(width: u16, max_lineno: u32) -> Self {
+        let lineno_width = max_lineno.max(1).to_string().len();
+        // One column goes to the separator
+        let side_width = (width as usize).saturating_sub(1) / 2;
+        Self {
+            lineno_width,
+            text_width: side_width.saturating_sub(lineno_width + 1),
+        }
+    }
+
+    fn cell(&self, cell: Cell) -> Vec<Span<'static>> {
+        let lineno = cell.lineno.map(|n| n.to_string()).unwrap_or_default();
+        let mut spans = vec![Span::styled(
+            format!("{lineno:>width$} ", width = self.lineno_width),
+            Style::default().add_modifier(Modifier::DIM),
+        )];
+
+        // Cut the text to the column, then pad it so the separator lines up
+        let text = clip_line(&Line::from(cell.text), 0, self.text_width);
+        let remaining = self.text_width.saturating_sub(text.width());
+        spans.extend(text.spans);
+        if remaining > 0 {
+            spans.push(Span::styled(" ".repeat(remaining), cell.base));
+        }
+        spans
+    }
+
+    fn blank(&self) -> Vec<Span<'static>> {
+        vec![Span::raw(
+            " ".repeat(self.lineno_width + 1 + self.text_width),
+        )]
+    }
+
+    fn row(&self, left: Vec<Span<'static>>, right: Vec<Span<'static>>) -> Line<'static> {
+        let mut spans = left;
+        spans.push(Span::styled(
+            SEPARATOR,
+            Style::default().add_modifier(Modifier::DIM),
+        ));
+        spans.extend(right);
+        Line::from(spans)
+    }
+}
+
+/// The display columns `offset..offset + width` of `line`, marking the edges where text
+/// continues beyond them. Wide characters cut in half by an edge become spaces.
+pub fn clip_line(line: &Line<'static>, offset: usize, width: usize) -> Line<'static> {
+    let end = offset + width;
+    // Visible characters with their style and width
+    let mut cells: Vec<(char, Style, usize)> = Vec::new();
+    let mut cut_left = false;
+    let mut cut_right = false;
+    let mut column = 0;
+    'spans: for span in &line.spans {
+        for c in span.content.chars() {
+            let char_width = c.width().unwrap_or(0);
+            let start = column;
+            column += char_width;
+            if start >= end && char_width > 0 {
+                cut_right = true;
+                break 'spans;
+            }
+            if column <= offset {
+                cut_left |= char_width > 0;
+            } else if start < offset {
+                cut_left = true;
+                cells.extend((offset..column.min(end)).map(|_| (' ', span.style, 1)));
+            } else if column > end {
+                cut_right = true;
+                cells.extend((start..end).map(|_| (' ', span.style, 1)));
+                break 'spans;
+            } else {
+                cells.push((c, span.style, char_width));
+            }
+        }
+    }
+
+    if cut_left {
+        replace_edge(&mut cells, CUT_LEFT_MARKER, false);
+    }
+    if cut_right {
+        replace_edge(&mut cells, CUT_RIGHT_MARKER, true);
+    }
+
+    let mut spans: Vec<Span<'static>> = Vec::new();
+    for (c, style, _) in cells {
+        match spans.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push(c),
+            _ => spans.push(Span::styled(c.to_string(), style)),
+        }
+    }
+    Line::from(spans).style(line.style)
+}
+
+/// Put `marker` in the first (or last) column of `cells`, padding with spaces when
+/// that takes a wide character's place
+fn replace_edge(cells: &mut Vec<(char, Style, usize)>, marker: char, last: bool) {
+    let removed = if last {
+        cells.pop()
+    } else {
+        cells.first().copied()
+    };
+    let Some((_, style, width)) = removed else {
+        return;
+    };
+    let mut replacement = vec![(marker, style, 1)];
+    replacement.extend((1..width).map(|_| (' ', style, 1)));
+    if last {
+        // The marker goes in the very last column, after any padding
+        cells.extend(replacement.into_iter().rev());
+    } else {
+        cells.splice(0..1, replacement);
+    }
+}
+
+/// `@@ -a,b +c,d @@` in the frag style, followed by the function context
+fn hunk_header(header: &str, styles: &DiffStyles) -> Line<'static> {
+    let split = header
+        .get(2..)
+        .and_then(|rest| rest.find("@@"))
+        .map(|pos| pos + 4)
+        .unwrap_or(header.len());
+    let (frag, func) = header.split_at(split);
+
+    let mut spans = vec![Span::styled(frag.to_string(), styles.frag)];
+    if !func.is_empty() {
+        spans.push(Span::styled(expand_tabs(func), styles.func));
+    }
+    Line::from(spans)
+}
+
+/// `expand_tabs` across spans, keeping track of the column from one span to the next
+fn expand_tabs_in_spans(spans: impl IntoIterator<Item = Span<'static>>) -> Vec<Span<'static>> {
+    let mut column = 0;
+    spans
+        .into_iter()
+        .map(|span| {
+            let mut out = String::with_capacity(span.content.len());
+            for c in span.content.chars() {
+                if c == '\t' {
+                    let spaces = TAB_WIDTH - column % TAB_WIDTH;
+                    out.push_str(&" ".repeat(spaces));
+                    column += spaces;
+                } else {
+                    out.push(c);
+                    column += 1;
+                }
+            }
+            Span::styled(out, span.style)
+        })
+        .collect()
+}
+
+fn expand_tabs(text: &str) -> String {
+    if !text.contains('\t') {
+        return text.to_string();
+    }
+    let mut out = String::with_capacity(text.len() + TAB_WIDTH);
+    let mut column = 0;
+    for c in text.chars() {
+        if c == '\t' {
+            let spaces = TAB_WIDTH - column % TAB_WIDTH;
+            out.push_str(&" ".repeat(spaces));
+            column += spaces;
+        } else {
+            out.push(c);
+            column += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(line: &Line) -> String {
+        line.spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect()
+    }
+
+    #[test]
+    fn clip_line_marks_cut_edges() {
+        let line = Line::from(vec![Span::raw("abc"), Span::raw("defgh")]);
+        assert_eq!(text(&clip_line(&line, 0, 8)), "abcdefgh");
+        assert_eq!(text(&clip_line(&line, 0, 4)), "abc›");
+        assert_eq!(text(&clip_line(&line, 2, 3)), "‹d›");
+        assert_eq!(text(&clip_line(&line, 5, 10)), "‹gh");
+        assert_eq!(text(&clip_line(&line, 8, 4)), "");
+    }
+
+    #[test]
+    fn clip_line_keeps_styles() {
+        let red = Style::default().fg(Color::Red);
+        let line = Line::from(vec![Span::raw("ab"), Span::styled("cd", red)]);
+        let clipped = clip_line(&line, 1, 3);
+        let spans: Vec<_> = clipped
+            .spans
+            .iter()
+            .map(|span| (span.content.as_ref(), span.style))
+            .collect();
+        assert_eq!(spans, [("‹", Style::default()), ("cd", red)]);
+    }
+
+    #[test]
+    fn clip_line_replaces_halves_of_wide_characters() {
+        let line = Line::raw("a漢字b");
+        // Columns: a=0, 漢=1-2, 字=3-4, b=5
+        assert_eq!(text(&clip_line(&line, 2, 4)), "‹字b");
+        assert_eq!(text(&clip_line(&line, 0, 2)), "a›");
+        assert_eq!(text(&clip_line(&line, 0, 4)), "a漢›");
+        assert_eq!(clip_line(&line, 2, 4).width(), 4);
+    }
+
+    fn numbers(rows: &[RowSource]) -> Vec<(Option<usize>, Option<u32>, Option<u32>)> {
+        rows.iter()
+            .map(|row| (row.hunk, row.old_lineno, row.new_lineno))
+            .collect()
+    }
+
+    #[test]
+    fn text_rows_number_lines_from_hunk_headers() {
+        let lines: Vec<Line<'static>> = [
+            "diff --git a/f b/f",
+            "@@ -3,3 +3,2 @@ fn f() {",
+            " a",
+            "-b",
+            "\\ No newline at end of file",
+            " c",
+            "@@ -9 +8,2 @@",
+            "+d",
+            " e",
+            "trailing text",
+        ]
+        .into_iter()
+        .map(Line::raw)
+        .collect();
+        assert_eq!(
+            numbers(&text_rows(&lines)),
+            [
+                (None, None, None),
+                (Some(0), None, None),
+                (Some(0), Some(3), Some(3)),
+                (Some(0), Some(4), None),
+                (Some(0), None, None),
+                (Some(0), Some(5), Some(4)),
+                (Some(1), None, None),
+                (Some(1), None, Some(8)),
+                (Some(1), Some(9), Some(9)),
+                (None, None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn text_rows_ignore_other_formats() {
+        let lines = vec![Line::raw("@@ not a hunk"), Line::raw(" text")];
+        assert_eq!(numbers(&text_rows(&lines)), [(None, None, None); 2]);
+    }
+
+    #[test]
+    fn gutter_fits_the_widest_number() {
+        assert!(Gutter::new(&[RowSource::default()]).is_none());
+
+        let rows = [
+            RowSource {
+                hunk: Some(0),
+                old_lineno: Some(99),
+                new_lineno: Some(100),
+            },
+            RowSource::default(),
+        ];
+        let gutter = Gutter::new(&rows).unwrap();
+        assert_eq!(gutter.width(), 8);
+        assert_eq!(gutter.span(Some(&rows[0])).content, " 99 100│");
+        assert_eq!(gutter.span(None).content, "       │");
+    }
+}
@@ -0,0 +1,291 @@
+use std::ops::Range;
+
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+use crate::git::{Hunk, LineKind};
+
+/// Paired lines less similar than this get no emphasis: when most of a line
+/// changed, marking every word is noise rather than help
+const MIN_SIMILARITY: f64 = 0.4;
+
+/// Lines longer than this aren't compared word by word
+const MAX_LINE_BYTES: usize = 4096;
+
+/// Byte ranges within a line
+pub type Ranges = Vec<Range<usize>>;
+
+/// How a word differs between the old and new version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordChange {
+    Equal,
+    Deleted,
+    Inserted,
+}
+
+/// Split text into words, runs of whitespace and single punctuation characters
+fn tokenize(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let same_class: fn(char) -> bool = if is_word_char(c) {
+            is_word_char
+        } else if c.is_whitespace() {
+            char::is_whitespace
+        } else {
+            tokens.push(&text[start..start + c.len_utf8()]);
+            continue;
+        };
+        let mut end = start + c.len_utf8();
+        while let Some(&(pos, next)) = chars.peek() {
+            if !same_class(next) {
+                break;
+            }
+            end = pos + next.len_utf8();
+            chars.next();
+        }
+        tokens.push(&text[start..end]);
+    }
+    tokens
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Byte ranges of the changed words in each line of `hunk`. Each run of removed
+/// lines is paired line by line with the run of added lines that follows it;
+/// other lines get no ranges.
+pub fn hunk_emphasis(hunk: &Hunk) -> Vec<Ranges> {
+    let mut emphasis = vec![Vec::new(); hunk.lines.len()];
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    let mut pair_block = |removed: &mut Vec<usize>, added: &mut Vec<usize>| {
+        for (&old_idx, &new_idx) in removed.iter().zip(added.iter()) {
+            let old = &hunk.lines[old_idx].content;
+            let new = &hunk.lines[new_idx].content;
+            if let Some((old_ranges, new_ranges)) = line_pair_emphasis(old, new) {
+                emphasis[old_idx] = old_ranges;
+                emphasis[new_idx] = new_ranges;
+            }
+        }
+        removed.clear();
+        added.clear();
+    };
+
+    for (idx, line) in hunk.lines.iter().enumerate() {
+        match line.kind {
+            LineKind::Removed if added.is_empty() => removed.push(idx),
+            LineKind::Removed => {
+                pair_block(&mut removed, &mut added);
+                removed.push(idx);
+            }
+            LineKind::Added => added.push(idx),
+            // "No newline" markers sit inside a block without ending it
+            LineKind::Marker => {}
+            LineKind::Context => pair_block(&mut removed, &mut added),
+        }
+    }
+    pair_block(&mut removed, &mut added);
+    emphasis
+}
+
+/// Changed byte ranges of an old and new line, or `None` when they have too little
+/// in common to be worth emphasizing
+fn line_pair_emphasis(old: &str, new: &str) -> Option<(Ranges, Ranges)> {
+    if old.len() > MAX_LINE_BYTES || new.len() > MAX_LINE_BYTES {
+        return None;
+    }
+    let old_tokens = tokenize(old);
+    let new_tokens = tokenize(new);
+    let old_offsets = offsets(&old_tokens);
+    let new_offsets = offsets(&new_tokens);
+
+    let mut equal_bytes = 0;
+    let mut old_ranges = Ranges::new();
+    let mut new_ranges = Ranges::new();
+    for op in capture_diff_slices(Algorithm::Myers, &old_tokens, &new_tokens) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        if tag == DiffTag::Equal {
+            equal_bytes += old_offsets[old_range.end] - old_offsets[old_range.start];
+            continue;
+        }
+        push_range(
+            &mut old_ranges,
+            old_offsets[old_range.start]..old_offsets[old_range.end],
+        );
+        push_range(
+            &mut new_ranges,
+            new_offsets[new_range.start]..new_offsets[new_range.end],
+        );
+    }
+
+    let total = old.len() + new.len();
+    if total == 0 || (2 * equal_bytes) as f64 / (total as f64) < MIN_SIMILARITY {
+        return None;
+    }
+    Some((old_ranges, new_ranges))
+}
+
+/// Byte offset of every token start, plus the end of the text
+fn offsets(tokens: &[&str]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(tokens.len() + 1);
+    let mut offset = 0;
+    offsets.push(0);
+    for token in tokens {
+        offset += token.len();
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// Append a range, merging it with the previous one when they touch
+fn push_range(ranges: &mut Ranges, range: Range<usize>) {
+    if range.is_empty() {
+        return;
+    }
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
+/// Merge a block of removed lines and the added lines replacing them into lines of
+/// words, like `git diff --word-diff`. Lines follow the new version; a line break
+/// that only exists in the old version is still shown as one.
+pub fn word_diff(old: &[&str], new: &[&str]) -> Vec<Vec<(WordChange, String)>> {
+    let old_tokens: Vec<&str> = old
+        .iter()
+        .flat_map(|line| tokenize(line).into_iter().chain(["\n"]))
+        .collect();
+    let new_tokens: Vec<&str> = new
+        .iter()
+        .flat_map(|line| tokenize(line).into_iter().chain(["\n"]))
+        .collect();
+
+    let mut lines = Vec::new();
+    let mut current: Vec<(WordChange, String)> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, &old_tokens, &new_tokens) {
+        let (tag, old_range, new_range) = op.as_tag_tuple();
+        let words = match tag {
+            DiffTag::Equal => vec![(WordChange::Equal, &old_tokens[old_range])],
+            DiffTag::Delete => vec![(WordChange::Deleted, &old_tokens[old_range])],
+            DiffTag::Insert => vec![(WordChange::Inserted, &new_tokens[new_range])],
+            DiffTag::Replace => vec![
+                (WordChange::Deleted, &old_tokens[old_range]),
+                (WordChange::Inserted, &new_tokens[new_range]),
+            ],
+        };
+        for (change, tokens) in words {
+            for &token in tokens {
+                if token == "\n" {
+                    lines.push(std::mem::take(&mut current));
+                    continue;
+                }
+                match current.last_mut() {
+                    Some((last, text)) if *last == change => text.push_str(token),
+                    _ => current.push((change, token.to_string())),
+                }
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::DiffLine;
+
+    fn hunk(lines: &[(LineKind, &str)]) -> Hunk {
+        Hunk {
+            header: "@@ -1 +1 @@".to_string(),
+            lines: lines
+                .iter()
+                .map(|&(kind, content)| DiffLine {
+                    kind,
+                    old_lineno: None,
+                    new_lineno: None,
+                    content: content.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn bounds(ranges: &[Range<usize>]) -> Vec<(usize, usize)> {
+        ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_splits_words_whitespace_and_punctuation() {
+        assert_eq!(
+            tokenize("let foo_bar  = baz(1);"),
+            ["let", " ", "foo_bar", "  ", "=", " ", "baz", "(", "1", ")", ";"]
+        );
+        assert_eq!(tokenize("ä→b"), ["ä", "→", "b"]);
+        assert!(tokenize("").is_empty());
+    }
+
+    #[test]
+    fn emphasis_marks_changed_words_of_paired_lines() {
+        let hunk = hunk(&[
+            (LineKind::Context, "fn main() {"),
+            (LineKind::Removed, "    let x = 1;"),
+            (LineKind::Added, "    let y = 1;"),
+            (LineKind::Context, "}"),
+        ]);
+        let emphasis = hunk_emphasis(&hunk);
+        assert!(emphasis[0].is_empty() && emphasis[3].is_empty());
+        assert_eq!(bounds(&emphasis[1]), [(8, 9)]);
+        assert_eq!(bounds(&emphasis[2]), [(8, 9)]);
+    }
+
+    #[test]
+    fn emphasis_pairs_each_block_separately() {
+        let hunk = hunk(&[
+            (LineKind::Removed, "a b c"),
+            (LineKind::Added, "a x c"),
+            (LineKind::Removed, "d e f"),
+            (LineKind::Marker, "\\ No newline at end of file"),
+            (LineKind::Added, "d e g"),
+        ]);
+        let emphasis = hunk_emphasis(&hunk);
+        assert_eq!(bounds(&emphasis[0]), [(2, 3)]);
+        assert_eq!(bounds(&emphasis[1]), [(2, 3)]);
+        assert_eq!(bounds(&emphasis[2]), [(4, 5)]);
+        assert!(emphasis[3].is_empty());
+        assert_eq!(bounds(&emphasis[4]), [(4, 5)]);
+    }
+
+    #[test]
+    fn emphasis_skips_dissimilar_and_unpaired_lines() {
+        let hunk = hunk(&[
+            (LineKind::Removed, "completely different"),
+            (LineKind::Removed, "unpaired"),
+            (LineKind::Added, "nothing alike here"),
+        ]);
+        assert!(hunk_emphasis(&hunk).iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn word_diff_follows_new_lines() {
+        let lines = word_diff(&["a b", "c"], &["a x", "c"]);
+        assert_eq!(
+            lines,
+            [
+                vec![
+                    (WordChange::Equal, "a ".to_string()),
+                    (WordChange::Deleted, "b".to_string()),
+                    (WordChange::Inserted, "x".to_string()),
+                ],
+                vec![(WordChange::Equal, "c".to_string())],
+            ]
+        );
+    }
+}